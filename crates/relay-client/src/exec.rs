@@ -0,0 +1,244 @@
+use rusty_relay_messages::Headers;
+use std::{
+    collections::BTreeMap,
+    process::{Output, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{
+        Semaphore,
+        mpsc::{self, error::TrySendError},
+    },
+};
+
+/// How many webhooks may wait for a running command to finish, any more are dropped.
+const MAX_QUEUED: usize = 100;
+
+/// Runs a local command for each webhook, with the body on stdin and the method and headers in
+/// `WEBHOOK_METHOD` and `WEBHOOK_HEADER_<NAME>` environment variables.
+#[derive(Debug)]
+pub struct Exec {
+    command: Arc<str>,
+    queue: mpsc::Sender<Job>,
+}
+
+#[derive(Debug)]
+struct Job {
+    method: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum ExecError {
+    Io(std::io::Error),
+    Timeout,
+}
+
+impl Exec {
+    pub fn new(command: &str, concurrency: usize, timeout: Duration) -> Self {
+        let command: Arc<str> = command.into();
+        let (queue, jobs) = mpsc::channel(MAX_QUEUED);
+        let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        tokio::spawn(dispatch(command.clone(), timeout, permits, jobs));
+
+        Self { command, queue }
+    }
+
+    /// Runs the command in the background, at most `concurrency` at a time in order of arrival.
+    pub fn run(&self, method: String, headers: Headers, body: Vec<u8>) {
+        let job = Job {
+            method,
+            headers,
+            body,
+        };
+        if let Err(TrySendError::Full(job)) = self.queue.try_send(job) {
+            output!(
+                "⚠️ WARNING: dropped webhook ({}), {MAX_QUEUED} webhooks are already waiting for `{}`",
+                job.method,
+                self.command
+            );
+        }
+    }
+}
+
+/// Starts a command for every queued webhook as soon as there is a free permit, the timeout
+/// only covers running the command, not waiting in the queue.
+async fn dispatch(
+    command: Arc<str>,
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+    mut jobs: mpsc::Receiver<Job>,
+) {
+    while let Some(job) = jobs.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let command = command.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let Job {
+                method,
+                headers,
+                body,
+            } = job;
+
+            let started = Instant::now();
+            match execute(&command, timeout, &method, &headers, body).await {
+                Ok(output) => {
+                    let elapsed = started.elapsed().as_millis();
+                    let icon = if output.status.success() {
+                        "⚙️"
+                    } else {
+                        "❌"
+                    };
+                    output!(
+                        "{icon} webhook ({method}) ran `{command}` in {elapsed}ms, {}",
+                        output.status
+                    );
+                    print_output(&output.stdout);
+                    print_output(&output.stderr);
+                }
+                Err(ExecError::Timeout) => output!(
+                    "⚠️ WARNING: webhook ({method}) command `{command}` was killed after {}s",
+                    timeout.as_secs()
+                ),
+                Err(ExecError::Io(err)) => {
+                    output!("⚠️ WARNING: webhook ({method}) command `{command}` failed: {err}")
+                }
+            }
+        });
+    }
+}
+
+async fn execute(
+    command: &str,
+    timeout: Duration,
+    method: &str,
+    headers: &Headers,
+    body: Vec<u8>,
+) -> Result<Output, ExecError> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("WEBHOOK_METHOD", method)
+        .envs(header_vars(headers))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(ExecError::Io)?;
+
+    let mut stdin = child.stdin.take();
+    let write = async move {
+        if let Some(stdin) = stdin.as_mut() {
+            // the command may not read its stdin at all
+            let _ = stdin.write_all(&body).await;
+        }
+        drop(stdin);
+    };
+
+    let run = async {
+        let (_, output) = tokio::join!(write, child.wait_with_output());
+        output.map_err(ExecError::Io)
+    };
+
+    // the child is killed when dropped on timeout
+    tokio::time::timeout(timeout, run)
+        .await
+        .map_err(|_| ExecError::Timeout)?
+}
+
+/// The headers as environment variables e.g: `X-GitHub-Event` becomes `WEBHOOK_HEADER_X_GITHUB_EVENT`,
+/// repeated headers are joined by a comma.
+fn header_vars(headers: &Headers) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::<String, String>::new();
+    for (name, value) in headers {
+        let name = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                _ => '_',
+            })
+            .collect::<String>();
+        let value = String::from_utf8_lossy(value);
+
+        vars.entry(format!("WEBHOOK_HEADER_{name}"))
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    vars
+}
+
+fn print_output(output: &[u8]) {
+    let output = String::from_utf8_lossy(output);
+    for line in output.lines() {
+        output!("   {line}");
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_execute() {
+        let headers: Headers = vec![
+            ("X-GitHub-Event".to_string(), b"push".to_vec()),
+            ("accept".to_string(), b"text/html".to_vec()),
+            ("Accept".to_string(), b"*/*".to_vec()),
+        ];
+        let output = execute(
+            "echo \"$WEBHOOK_METHOD $WEBHOOK_HEADER_X_GITHUB_EVENT $WEBHOOK_HEADER_ACCEPT\"; cat; exit 3",
+            Duration::from_secs(5),
+            "POST",
+            &headers,
+            b"{\"ref\":\"main\"}".to_vec(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "POST push text/html, */*\n{\"ref\":\"main\"}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout() {
+        let result = execute(
+            "sleep 5",
+            Duration::from_millis(100),
+            "POST",
+            &vec![],
+            vec![],
+        )
+        .await;
+        assert!(matches!(result, Err(ExecError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_queue_is_bounded() {
+        let exec = Exec::new("sleep 5", 1, Duration::from_secs(10));
+        for _ in 0..MAX_QUEUED + 5 {
+            exec.run("POST".to_string(), vec![], vec![]);
+        }
+        // one command runs and one awaits its permit, the others wait in the queue or are dropped
+        assert_eq!(exec.queue.capacity(), 0);
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::Context;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use rusty_relay_messages::Headers;
+use serde_json::{Map, Value, json};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Shows the webhooks as they are received, pretty printed in the terminal and/or written as
+/// NDJSON (one JSON object per line) to a file.
+#[derive(Debug)]
+pub struct Inspector {
+    print: bool,
+    capture: Option<Mutex<File>>,
+}
+
+impl Inspector {
+    pub fn new(print: bool, capture: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let capture = capture
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open capture file: {path}"))
+            })
+            .transpose()?
+            .map(Mutex::new);
+
+        Ok((print || capture.is_some()).then_some(Self { print, capture }))
+    }
+
+    pub fn inspect(&self, method: &str, headers: &Headers, body: &[u8]) {
+        if self.print {
+            output!("{}", pretty(method, headers, body));
+        }
+
+        if let Some(capture) = &self.capture {
+            let mut file = capture.lock().expect("lock should not be poisoned");
+            if let Err(err) = writeln!(file, "{}", record(method, headers, body)) {
+                output!("⚠️ WARNING: failed to capture webhook ({method}): {err}");
+            }
+        }
+    }
+}
+
+/// The webhook with a table of its headers and its body formatted by content type.
+fn pretty(method: &str, headers: &Headers, body: &[u8]) -> String {
+    let mut out = format!("📨 webhook ({method}), {} bytes\n", body.len());
+
+    let width = headers
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, value) in headers {
+        out.push_str(&format!(
+            "   {name:width$}  {}\n",
+            String::from_utf8_lossy(value)
+        ));
+    }
+
+    let body = format_body(content_type(headers).as_deref(), body);
+    if !body.is_empty() {
+        out.push('\n');
+        for line in body.lines() {
+            out.push_str(&format!("   {line}\n"));
+        }
+    }
+    out
+}
+
+/// The body formatted for reading: JSON indented, form fields decoded and binary data summarized.
+pub fn format_body(content_type: Option<&str>, body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+
+    if content_type.is_some_and(|content_type| content_type.contains("x-www-form-urlencoded")) {
+        let pairs: Vec<(String, String)> = form_urlencoded::parse(body).into_owned().collect();
+        let width = pairs.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+        return pairs
+            .iter()
+            .map(|(key, value)| format!("{key:width$} = {value}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    // providers don't always send a json content type
+    if let Ok(json) = serde_json::from_slice::<Value>(body) {
+        if let Ok(pretty) = serde_json::to_string_pretty(&json) {
+            return pretty;
+        }
+    }
+
+    match std::str::from_utf8(body) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("<{} bytes of binary data>", body.len()),
+    }
+}
+
+/// The webhook as a single line of JSON, a body that isn't UTF-8 is base64 encoded.
+fn record(method: &str, headers: &Headers, body: &[u8]) -> Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+
+    let mut header_map = Map::new();
+    for (name, value) in headers {
+        let value = String::from_utf8_lossy(value);
+        match header_map.get_mut(name) {
+            Some(Value::String(joined)) => {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            }
+            _ => {
+                header_map.insert(name.clone(), Value::String(value.to_string()));
+            }
+        }
+    }
+
+    let mut record = json!({
+        "timestamp": timestamp,
+        "method": method,
+        "headers": header_map,
+    });
+    match std::str::from_utf8(body) {
+        Ok(text) => record["body"] = Value::String(text.to_string()),
+        Err(_) => record["body_base64"] = Value::String(BASE64_STANDARD.encode(body)),
+    }
+    record
+}
+
+fn content_type(headers: &Headers) -> Option<String> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| String::from_utf8_lossy(value).to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty() {
+        let headers: Headers = vec![
+            ("content-type".to_string(), b"application/json".to_vec()),
+            ("x-github-event".to_string(), b"push".to_vec()),
+        ];
+        assert_eq!(
+            pretty("POST", &headers, br#"{"ref":"main"}"#),
+            "📨 webhook (POST), 14 bytes\n   \
+             content-type    application/json\n   \
+             x-github-event  push\n\n   \
+             {\n     \"ref\": \"main\"\n   }\n"
+        );
+
+        let form = format_body(
+            Some("application/x-www-form-urlencoded"),
+            b"command=%2Fdeploy&text=hello+world",
+        );
+        assert_eq!(form, "command = /deploy\ntext    = hello world");
+        assert_eq!(format_body(None, &[0xff, 0xfe]), "<2 bytes of binary data>");
+    }
+
+    #[test]
+    fn test_record() {
+        let headers: Headers = vec![
+            ("accept".to_string(), b"text/html".to_vec()),
+            ("accept".to_string(), b"*/*".to_vec()),
+        ];
+        let record = record("POST", &headers, b"hello");
+        assert_eq!(record["method"], "POST");
+        assert_eq!(record["headers"]["accept"], "text/html, */*");
+        assert_eq!(record["body"], "hello");
+
+        let record = super::record("POST", &vec![], &[0xff]);
+        assert_eq!(record["body_base64"], "/w==");
+        assert!(record.get("body").is_none());
+    }
+}
@@ -1,12 +1,32 @@
 #![allow(clippy::collapsible_if)]
 
-use anyhow::Context;
+/// Prints a line, or adds it to the log of the terminal UI when it is running.
+macro_rules! output {
+    ($($arg:tt)*) => {
+        $crate::tui::output(format!($($arg)*))
+    };
+}
 
-use crate::{proxy::ProxyHandler, webhook::WebhookHandler};
+use crate::{
+    exec::Exec,
+    inspect::Inspector,
+    proxy::{ProxyHandler, Upstream},
+    rewrite::Rules,
+    serve::StaticFiles,
+    tui::{App, Event},
+    webhook::WebhookHandler,
+};
+use std::time::Duration;
 
 mod cli;
+mod dialer;
+mod exec;
+mod inspect;
 mod proxy;
+mod rewrite;
+mod serve;
 mod tls;
+mod tui;
 mod version;
 mod webhook;
 mod websocket;
@@ -20,17 +40,68 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let args = cli::args();
+    let events = args.tui.then(tui::init);
+
+    let rules = match args.rewrite_rules.as_deref() {
+        Some(path) => Rules::load(path)?,
+        None => Rules::default(),
+    };
+
+    let upstream = match (&args.serve, &args.target) {
+        (Some(dir), _) => {
+            let files = StaticFiles::new(dir, args.listing, args.spa)?;
+            output!("📂 serving files of: {}", files.root().display());
+            Some(Upstream::Files(files))
+        }
+        (None, Some(target)) => Some(Upstream::Target(&target.url)),
+        (None, None) => None,
+    };
+    let exec = args.exec.as_deref().map(|command| {
+        output!("⚙️ running for each webhook: {command}");
+        Exec::new(
+            command,
+            args.exec_concurrency,
+            Duration::from_secs(args.exec_timeout),
+        )
+    });
 
-    let http_client = reqwest::Client::builder()
-        .tls_backend_rustls()
-        .build()
-        .context("failed to build reqwest http client")?;
-    let webhook_handler = WebhookHandler::new(&args.target, http_client.clone());
-    let proxy_handler = ProxyHandler::new(&args.target, http_client);
+    let http_client = tls::http_client(&args)?;
+    let webhook_handler = WebhookHandler::new(
+        args.target.as_ref().map(|target| target.url.as_str()),
+        exec,
+        Inspector::new(args.print, args.capture.as_deref())?,
+        http_client.clone(),
+        &rules,
+    );
+    let proxy_handler = ProxyHandler::new(upstream, http_client, &rules);
 
     let ws_client = websocket::Client::new(&args, webhook_handler, proxy_handler);
 
-    ws_client.connect_blocking().await?;
+    let Some(events) = events else {
+        return ws_client.connect_blocking(None).await;
+    };
 
-    Ok(())
+    let upstream = match (&args.serve, &args.target, &args.exec) {
+        (Some(dir), ..) => dir.clone(),
+        (None, Some(target), _) => target.url.clone(),
+        (None, None, Some(command)) => command.clone(),
+        (None, None, None) => "webhooks".to_string(),
+    };
+    let (replays, replay_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let app = App::new(&args.server, &upstream, replays);
+
+    // the terminal UI stays open after a disconnect, showing the reason
+    let connection = async {
+        let reason = match ws_client.connect_blocking(Some(replay_receiver)).await {
+            Ok(()) => "connection closed".to_string(),
+            Err(err) => format!("{err:#}"),
+        };
+        tui::report(Event::Disconnected(reason));
+        std::future::pending::<()>().await
+    };
+
+    tokio::select! {
+        result = app.run(events) => result,
+        _ = connection => Ok(()),
+    }
 }
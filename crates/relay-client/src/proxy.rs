@@ -1,22 +1,40 @@
+use crate::{
+    rewrite::{Direction, Rules},
+    serve::StaticFiles,
+    tui::{self, Kind},
+};
 use anyhow::Context;
 use reqwest::{
-    Client, Method,
+    Client, Method, Url,
     header::{HeaderMap, HeaderName, HeaderValue},
 };
-use rusty_relay_messages::RelayMessage;
-use std::{collections::HashMap, str::FromStr};
+use rusty_relay_messages::{Headers, ProxyErrorKind, RelayMessage};
+use std::{error::Error, str::FromStr, sync::RwLock};
+
+/// Where proxy requests are answered.
+#[derive(Debug)]
+pub enum Upstream<'a> {
+    /// Forwarded to the local webserver at this url
+    Target(&'a str),
+    /// Answered with the files of a local directory
+    Files(StaticFiles),
+}
 
 #[derive(Debug)]
 pub struct ProxyHandler<'a> {
-    target: &'a str,
+    upstream: Option<Upstream<'a>>,
     http_client: Client,
+    rules: &'a Rules,
+    proxy_url: RwLock<String>,
 }
 
 impl<'a> ProxyHandler<'a> {
-    pub fn new(target: &'a str, http_client: Client) -> Self {
+    pub fn new(upstream: Option<Upstream<'a>>, http_client: Client, rules: &'a Rules) -> Self {
         Self {
-            target,
+            upstream,
             http_client,
+            rules,
+            proxy_url: RwLock::default(),
         }
     }
 
@@ -26,71 +44,372 @@ impl<'a> ProxyHandler<'a> {
         path: Option<String>,
         query: Option<String>,
         method: String,
-        headers: HashMap<String, String>,
-        body: Vec<u8>,
-    ) -> anyhow::Result<Option<RelayMessage>> {
-        let url = if let Some(p) = path.as_ref() {
-            &format!("{}/{}", self.target, p)
-        } else {
-            self.target
-        };
-        let url = if let Some(q) = query.as_ref() {
-            &format!("{url}{q}")
-        } else {
-            url
+        mut headers: Headers,
+        mut body: Vec<u8>,
+    ) -> RelayMessage {
+        let recorded = tui::record(
+            Kind::Proxy,
+            &method,
+            path.as_deref(),
+            query.as_deref(),
+            &headers,
+            &body,
+        );
+        let proxy_url = self
+            .proxy_url
+            .read()
+            .expect("lock should not be poisoned")
+            .clone();
+        let route = path.as_deref().unwrap_or_default();
+        self.rules.apply(
+            Direction::Request,
+            route,
+            &mut headers,
+            &mut body,
+            &proxy_url,
+        );
+        if self.rules.rewrites_response_body() {
+            // a compressed response can't be rewritten
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case("accept-encoding"));
+        }
+
+        let response = match &self.upstream {
+            Some(Upstream::Target(target)) => {
+                match target_url(target, path.as_deref(), query.as_deref()) {
+                    Ok(url) => self
+                        .forward(url.clone(), &method, headers, body)
+                        .await
+                        .inspect_err(|(_, message)| {
+                            output!("⚠️ WARNING: request ({method}) to {url} failed: {message}")
+                        }),
+                    Err(err) => Err((ProxyErrorKind::InvalidRequest, format!("{err:#}"))),
+                }
+            }
+            Some(Upstream::Files(files)) => Ok(files.respond(&method, route, &headers).await),
+            None => Err((
+                ProxyErrorKind::TargetUnreachable,
+                "the client has no target for proxy requests".to_string(),
+            )),
         };
 
+        match response {
+            Ok((status, mut headers, mut body)) => {
+                if let Some(Upstream::Target(target)) = self.upstream {
+                    rewrite_to_public(&mut headers, target, &proxy_url);
+                }
+                self.rules.apply(
+                    Direction::Response,
+                    route,
+                    &mut headers,
+                    &mut body,
+                    &proxy_url,
+                );
+                if recorded.is_some() {
+                    let response = (status, headers.clone(), body.clone());
+                    tui::report_exchange(recorded, Some(response), None);
+                }
+                RelayMessage::ProxyResponse {
+                    request_id,
+                    headers,
+                    body,
+                    status,
+                }
+            }
+            Err((kind, message)) => {
+                tui::report_exchange(recorded, None, Some(message.clone()));
+                RelayMessage::ProxyError {
+                    request_id,
+                    kind,
+                    message,
+                }
+            }
+        }
+    }
+
+    /// Sends the request to the target, returning the status, headers and body of its response.
+    async fn forward(
+        &self,
+        url: Url,
+        method: &str,
+        headers: Headers,
+        body: Vec<u8>,
+    ) -> Result<(u16, Headers, Vec<u8>), (ProxyErrorKind, String)> {
+        let invalid = |message: String| (ProxyErrorKind::InvalidRequest, message);
+
         let mut request_headers = HeaderMap::with_capacity(headers.len());
         for (k, v) in headers {
-            request_headers.insert(
-                k.parse::<HeaderName>().with_context(|| {
-                    format!("failed to parse key: {} as header name for proxy", &k)
-                })?,
-                v.parse::<HeaderValue>().with_context(|| {
-                    format!("failed to parse value: {} as header value for proxy", &v)
-                })?,
+            request_headers.append(
+                k.parse::<HeaderName>()
+                    .map_err(|_| invalid(format!("invalid header name: {k}")))?,
+                HeaderValue::from_bytes(&v)
+                    .map_err(|_| invalid(format!("invalid value for header: {k}")))?,
             );
         }
+        let method = Method::from_str(method)
+            .map_err(|_| invalid(format!("invalid http method: {method}")))?;
 
-        let response = self
+        let res = self
             .http_client
-            .request(
-                Method::from_str(&method)
-                    .with_context(|| format!("failed to parse http method: {}", &method))?,
-                url,
-            )
+            .request(method, url)
             .headers(request_headers)
             .body(body)
             .send()
             .await
-            .map_err(|err| println!("⚠️ WARNING: request ({method}) to {url} failed: {err}"));
+            .map_err(target_error)?;
 
-        if let Ok(res) = response {
-            let mut response_headers = HashMap::new();
-            for (k, v) in res.headers() {
-                if let Ok(value) = v.to_str() {
-                    response_headers.insert(k.to_string(), value.to_string());
-                }
-            }
-            let status = res.status().as_u16();
-
-            return Ok(Some(RelayMessage::ProxyResponse {
-                request_id,
-                body: res
-                    .bytes()
-                    .await
-                    .context("failed to parse proxy response body bytes")?
-                    .to_vec(),
-                headers: response_headers,
-                status,
-            }));
-        }
+        let response_headers = res
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect();
+        let status = res.status().as_u16();
 
-        Ok(None)
+        let body = res.bytes().await.map_err(target_error)?.to_vec();
+
+        Ok((status, response_headers, body))
     }
 
-    pub fn print_url(&self, client_id: &str, protocol: &str, server: &str) {
+    pub fn print_url(&self, client_id: &str, protocol: &str, server: &str) -> String {
         let proxy_url = format!("{}{}/proxy/{}", protocol, server, client_id);
-        println!("✅ You can serve api or website at: {proxy_url}")
+        output!("✅ You can serve api or website at: {proxy_url}");
+        *self.proxy_url.write().expect("lock should not be poisoned") = proxy_url.clone();
+        proxy_url
+    }
+}
+
+/// Appends the raw path and query of a proxy request to the target, keeping their encoding.
+fn target_url(target: &str, path: Option<&str>, query: Option<&str>) -> anyhow::Result<Url> {
+    let mut url =
+        Url::parse(target).with_context(|| format!("failed to parse target url: {target}"))?;
+
+    if let Some(path) = path.filter(|path| !path.is_empty()) {
+        let base = url.path().trim_end_matches('/');
+        url.set_path(&format!("{base}/{path}"));
+    }
+    if let Some(query) = query {
+        let query = match url.query() {
+            Some(target_query) if !target_query.is_empty() => format!("{target_query}&{query}"),
+            _ => query.to_string(),
+        };
+        url.set_query(Some(&query));
+    }
+
+    Ok(url)
+}
+
+/// Rewrites the `Location`, `Content-Location` and `Set-Cookie` headers of a response pointing
+/// to the target, so redirects and cookies stay under the proxy url.
+fn rewrite_to_public(headers: &mut Headers, target: &str, proxy_url: &str) {
+    let (Ok(target), Ok(public)) = (Url::parse(target), Url::parse(proxy_url)) else {
+        return;
+    };
+    let base = target.path().trim_end_matches('/');
+    let prefix = public.path().trim_end_matches('/');
+
+    for (name, value) in headers.iter_mut() {
+        let Ok(text) = std::str::from_utf8(value) else {
+            continue;
+        };
+        let rewritten = if name.eq_ignore_ascii_case("location")
+            || name.eq_ignore_ascii_case("content-location")
+        {
+            public_location(text, &target, base, proxy_url.trim_end_matches('/'), prefix)
+        } else if name.eq_ignore_ascii_case("set-cookie") {
+            Some(public_cookie(text, base, prefix))
+        } else {
+            None
+        };
+
+        if let Some(rewritten) = rewritten {
+            *value = rewritten.into_bytes();
+        }
+    }
+}
+
+/// The location on the proxy url, or `None` when it doesn't point to the target.
+fn public_location(
+    location: &str,
+    target: &Url,
+    base: &str,
+    proxy_url: &str,
+    prefix: &str,
+) -> Option<String> {
+    if location.starts_with('/') && !location.starts_with("//") {
+        return strip_base(location, base).map(|rest| format!("{prefix}{rest}"));
+    }
+
+    let url = Url::parse(location).ok()?;
+    if url.origin() != target.origin() {
+        return None;
+    }
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push_str(&format!("?{query}"));
+    }
+    if let Some(fragment) = url.fragment() {
+        path.push_str(&format!("#{fragment}"));
+    }
+
+    strip_base(&path, base).map(|rest| format!("{proxy_url}{rest}"))
+}
+
+/// The cookie without its `Domain`, so it belongs to the server, and with its `Path` moved
+/// under the proxy url.
+fn public_cookie(cookie: &str, base: &str, prefix: &str) -> String {
+    let mut parts = cookie.split(';').map(str::trim);
+    let mut rewritten = vec![parts.next().unwrap_or_default().to_string()];
+
+    for attribute in parts {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        if key.trim().eq_ignore_ascii_case("domain") {
+            continue;
+        }
+        if key.trim().eq_ignore_ascii_case("path") {
+            // a path outside the target becomes the whole proxy url
+            let rest = strip_base(value.trim(), base).unwrap_or_default();
+            let path = format!("{prefix}{rest}");
+            let path = match path.trim_end_matches('/') {
+                "" => "/",
+                path => path,
+            };
+            rewritten.push(format!("Path={path}"));
+            continue;
+        }
+        rewritten.push(attribute.to_string());
+    }
+
+    rewritten.join("; ")
+}
+
+/// The rest of an absolute path after the path of the target, `None` if it is outside of it.
+fn strip_base<'p>(path: &'p str, base: &str) -> Option<&'p str> {
+    let rest = path.strip_prefix(base)?;
+    (rest.is_empty() || rest.starts_with(['/', '?', '#'])).then_some(rest)
+}
+
+fn target_error(err: reqwest::Error) -> (ProxyErrorKind, String) {
+    let kind = if err.is_timeout() {
+        ProxyErrorKind::TargetTimeout
+    } else {
+        ProxyErrorKind::TargetUnreachable
+    };
+
+    // the cause (e.g: connection refused) is only in the source chain
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+
+    (kind, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(target: &str, path: Option<&str>, query: Option<&str>) -> String {
+        target_url(target, path, query).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_target_url() {
+        let target = "http://localhost:3000";
+        assert_eq!(url(target, None, None), "http://localhost:3000/");
+        assert_eq!(
+            url(target, Some("api/users"), Some("q=a%26b")),
+            "http://localhost:3000/api/users?q=a%26b"
+        );
+
+        // encoded slashes and unicode are kept as received
+        assert_eq!(
+            url(target, Some("files/a%2Fb/caf%C3%A9"), None),
+            "http://localhost:3000/files/a%2Fb/caf%C3%A9"
+        );
+        assert_eq!(
+            url(target, Some("café"), Some("name=jos%C3%A9")),
+            "http://localhost:3000/caf%C3%A9?name=jos%C3%A9"
+        );
+
+        // empty values and repeated keys
+        assert_eq!(
+            url(target, None, Some("a=&b&c=1&c=2")),
+            "http://localhost:3000/?a=&b&c=1&c=2"
+        );
+    }
+
+    #[test]
+    fn test_target_url_with_base() {
+        assert_eq!(
+            url("http://localhost:3000/api/", Some("users"), None),
+            "http://localhost:3000/api/users"
+        );
+        assert_eq!(
+            url(
+                "http://localhost:3000/api?key=1",
+                Some("users"),
+                Some("page=2")
+            ),
+            "http://localhost:3000/api/users?key=1&page=2"
+        );
+        assert!(target_url("/api", None, None).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_to_public() {
+        let proxy_url = "https://relay.example.com/proxy/shop";
+        let mut headers: Headers = vec![
+            (
+                "Location".to_string(),
+                b"http://localhost:3000/login?next=%2F".to_vec(),
+            ),
+            ("content-location".to_string(), b"/orders/1".to_vec()),
+            (
+                "set-cookie".to_string(),
+                b"session=abc; Domain=localhost; Path=/; HttpOnly".to_vec(),
+            ),
+            ("set-cookie".to_string(), b"cart=1; path=/cart".to_vec()),
+        ];
+        rewrite_to_public(&mut headers, "http://localhost:3000", proxy_url);
+
+        let values: Vec<&str> = headers
+            .iter()
+            .map(|(_, value)| std::str::from_utf8(value).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                "https://relay.example.com/proxy/shop/login?next=%2F",
+                "/proxy/shop/orders/1",
+                "session=abc; Path=/proxy/shop; HttpOnly",
+                "cart=1; Path=/proxy/shop/cart",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rewrite_to_public_with_base() {
+        let target = "http://localhost:3000/app";
+        let proxy_url = "https://relay.example.com/proxy/shop";
+        let location = |location: &str| {
+            let mut headers: Headers = vec![("location".to_string(), location.as_bytes().to_vec())];
+            rewrite_to_public(&mut headers, target, proxy_url);
+            String::from_utf8(headers.remove(0).1).unwrap()
+        };
+
+        assert_eq!(location("/app/login"), "/proxy/shop/login");
+        assert_eq!(location("/app"), "/proxy/shop");
+        // outside the target or another origin are left alone
+        assert_eq!(location("/application"), "/application");
+        assert_eq!(
+            location("http://localhost:4000/app"),
+            "http://localhost:4000/app"
+        );
+        assert_eq!(
+            location("https://github.com/login"),
+            "https://github.com/login"
+        );
+        assert_eq!(location("login"), "login");
     }
 }
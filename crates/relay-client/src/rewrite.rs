@@ -0,0 +1,298 @@
+use anyhow::Context;
+use regex::Regex;
+use rusty_relay_messages::{Headers, path_matches};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+/// Placeholder in replacements for the public url of this client (the proxy url, or the
+/// webhook url for webhooks).
+const PUBLIC_URL: &str = "{public_url}";
+
+/// Rewrite rules loaded from a TOML file, applied in order to the requests sent to the
+/// target and the proxy responses coming back.
+///
+/// ```toml
+/// [[rule]]
+/// set_headers = { Authorization = "Bearer dev-token" }
+/// remove_headers = ["Origin"]
+///
+/// [[rule]]
+/// on = "response"
+/// path = "/app/*"
+/// replace = [{ pattern = "http://localhost:3000", replacement = "{public_url}" }]
+/// location = [{ pattern = "^http://localhost:3000", replacement = "{public_url}" }]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Request,
+    Response,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    on: Direction,
+    /// Only apply to this route, a trailing `*` matches anything e.g: /api/*
+    path: Option<String>,
+    /// Added next to existing headers with the same name
+    #[serde(default)]
+    add_headers: BTreeMap<String, String>,
+    /// Replace existing headers with the same name
+    #[serde(default)]
+    set_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    remove_headers: Vec<String>,
+    /// Replacements in text bodies (e.g: html, json or javascript)
+    #[serde(default)]
+    replace: Vec<Replace>,
+    /// Replacements in the `Location` header of redirects
+    #[serde(default)]
+    location: Vec<Replace>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Replace {
+    #[serde(deserialize_with = "regex")]
+    pattern: Regex,
+    replacement: String,
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+impl Rules {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let rules = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read rewrite rules: {path}"))?;
+        toml::from_str(&rules).with_context(|| format!("failed to parse rewrite rules: {path}"))
+    }
+
+    /// Whether a response body may be rewritten, the target should then not compress it.
+    pub fn rewrites_response_body(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.on == Direction::Response && !rule.replace.is_empty())
+    }
+
+    /// Applies the matching rules to the headers and body of a request or response.
+    pub fn apply(
+        &self,
+        on: Direction,
+        path: &str,
+        headers: &mut Headers,
+        body: &mut Vec<u8>,
+        public_url: &str,
+    ) {
+        let rules = self.rules.iter().filter(|rule| {
+            rule.on == on
+                && rule
+                    .path
+                    .as_deref()
+                    .is_none_or(|pattern| path_matches(pattern, path))
+        });
+
+        for rule in rules {
+            rule.apply(headers, body, public_url);
+        }
+    }
+}
+
+impl Rule {
+    fn apply(&self, headers: &mut Headers, body: &mut Vec<u8>, public_url: &str) {
+        headers.retain(|(name, _)| {
+            !self
+                .remove_headers
+                .iter()
+                .chain(self.set_headers.keys())
+                .any(|removed| removed.eq_ignore_ascii_case(name))
+        });
+        for (name, value) in self.add_headers.iter().chain(&self.set_headers) {
+            headers.push((name.clone(), value.as_bytes().to_vec()));
+        }
+
+        for (name, value) in headers.iter_mut() {
+            if name.eq_ignore_ascii_case("location") {
+                if let Some(location) = replace_all(&self.location, value, public_url) {
+                    *value = location.into_bytes();
+                }
+            }
+        }
+
+        if is_text(headers) {
+            if let Some(replaced) = replace_all(&self.replace, body, public_url) {
+                *body = replaced.into_bytes();
+                // the length changed, it is set again from the body
+                headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+            }
+        }
+    }
+}
+
+/// The text with all replacements applied, or `None` when nothing was replaced.
+fn replace_all(replacements: &[Replace], text: &[u8], public_url: &str) -> Option<String> {
+    if replacements.is_empty() {
+        return None;
+    }
+
+    let original = std::str::from_utf8(text).ok()?;
+    let mut text = original.to_string();
+    for Replace {
+        pattern,
+        replacement,
+    } in replacements
+    {
+        let replacement = replacement.replace(PUBLIC_URL, public_url);
+        text = pattern
+            .replace_all(&text, replacement.as_str())
+            .into_owned();
+    }
+
+    (text != original).then_some(text)
+}
+
+/// Whether the body is uncompressed text, other bodies are never rewritten.
+fn is_text(headers: &Headers) -> bool {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+            .map(str::to_ascii_lowercase)
+    };
+
+    let compressed = header("content-encoding").is_some_and(|encoding| encoding != "identity");
+    let text = header("content-type").is_some_and(|content_type| {
+        content_type.starts_with("text/")
+            || ["json", "javascript", "xml"]
+                .iter()
+                .any(|kind| content_type.contains(kind))
+    });
+
+    text && !compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+        [[rule]]
+        set_headers = { Authorization = "Bearer dev-token" }
+        remove_headers = ["Origin"]
+
+        [[rule]]
+        on = "response"
+        path = "/app/*"
+        replace = [{ pattern = "http://localhost:3000", replacement = "{public_url}" }]
+        location = [{ pattern = "^http://localhost:3000", replacement = "{public_url}" }]
+    "#;
+
+    const PUBLIC_URL: &str = "https://relay.example.com/proxy/shop";
+
+    fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| std::str::from_utf8(value).unwrap())
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let rules: Rules = toml::from_str(RULES).unwrap();
+        let mut headers: Headers = vec![
+            ("origin".to_string(), b"https://evil.example.com".to_vec()),
+            ("authorization".to_string(), b"Basic old".to_vec()),
+            ("accept".to_string(), b"*/*".to_vec()),
+        ];
+        let mut body = Vec::new();
+        rules.apply(
+            Direction::Request,
+            "api",
+            &mut headers,
+            &mut body,
+            PUBLIC_URL,
+        );
+
+        assert_eq!(header(&headers, "origin"), None);
+        assert_eq!(header(&headers, "authorization"), Some("Bearer dev-token"));
+        assert_eq!(header(&headers, "accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn test_response_body_and_location() {
+        let rules: Rules = toml::from_str(RULES).unwrap();
+        assert!(rules.rewrites_response_body());
+
+        let mut headers: Headers = vec![
+            ("content-type".to_string(), b"text/html".to_vec()),
+            ("content-length".to_string(), b"42".to_vec()),
+            (
+                "location".to_string(),
+                b"http://localhost:3000/app/login".to_vec(),
+            ),
+        ];
+        let mut body = br#"<a href="http://localhost:3000/app/a">a</a>"#.to_vec();
+        rules.apply(
+            Direction::Response,
+            "app/home",
+            &mut headers,
+            &mut body,
+            PUBLIC_URL,
+        );
+
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"<a href="https://relay.example.com/proxy/shop/app/a">a</a>"#
+        );
+        assert_eq!(
+            header(&headers, "location"),
+            Some("https://relay.example.com/proxy/shop/app/login")
+        );
+        assert_eq!(header(&headers, "content-length"), None);
+
+        // other routes and binary bodies are left alone
+        let mut body = b"http://localhost:3000".to_vec();
+        rules.apply(
+            Direction::Response,
+            "api",
+            &mut headers,
+            &mut body,
+            PUBLIC_URL,
+        );
+        assert_eq!(body, b"http://localhost:3000");
+
+        let mut headers: Headers = vec![("content-type".to_string(), b"image/png".to_vec())];
+        rules.apply(
+            Direction::Response,
+            "app/logo",
+            &mut headers,
+            &mut body,
+            PUBLIC_URL,
+        );
+        assert_eq!(body, b"http://localhost:3000");
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(
+            toml::from_str::<Rules>(
+                "[[rule]]\nreplace = [{ pattern = \"(\", replacement = \"\" }]"
+            )
+            .is_err()
+        );
+        assert!(toml::from_str::<Rules>("[[rule]]\nunknown = true").is_err());
+    }
+}
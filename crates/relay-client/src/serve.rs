@@ -0,0 +1,405 @@
+use anyhow::Context;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use rusty_relay_messages::Headers;
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+const INDEX: &str = "index.html";
+
+/// Characters encoded in the links of a directory listing.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+type Response = (u16, Headers, Vec<u8>);
+
+/// Answers proxy requests with the files of a local directory, instead of a target.
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    /// List the files of directories without an index.html
+    listing: bool,
+    /// Answer unknown paths without a file extension with the root index.html
+    spa: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: &str, listing: bool, spa: bool) -> anyhow::Result<Self> {
+        let root = std::fs::canonicalize(root)
+            .with_context(|| format!("failed to open directory to serve: {root}"))?;
+        anyhow::ensure!(root.is_dir(), "not a directory: {}", root.display());
+
+        Ok(Self { root, listing, spa })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Responds to a `GET` or `HEAD` request for the (still percent-encoded) path.
+    pub async fn respond(&self, method: &str, path: &str, headers: &Headers) -> Response {
+        if method != "GET" && method != "HEAD" {
+            return text(405, "Method Not Allowed", &[("allow", "GET, HEAD")]);
+        }
+
+        let mut response = self.get(path, headers).await;
+        if method == "HEAD" {
+            response.2.clear();
+        }
+        response
+    }
+
+    async fn get(&self, path: &str, headers: &Headers) -> Response {
+        let Some(segments) = segments(path) else {
+            return not_found();
+        };
+        let Some(file) = self.resolve(&segments).await else {
+            return match self.spa && !has_extension(&segments) {
+                true => self.file(&self.root.join(INDEX), None).await,
+                false => not_found(),
+            };
+        };
+
+        if !file.is_dir() {
+            return self.file(&file, header(headers, "range")).await;
+        }
+
+        // relative links only work from a path ending with a slash
+        if !path.is_empty() && !path.ends_with('/') {
+            let location = format!("{}/", path.rsplit('/').next().unwrap_or_default());
+            return text(301, "Moved Permanently", &[("location", &location)]);
+        }
+
+        let index = file.join(INDEX);
+        if index.is_file() {
+            self.file(&index, header(headers, "range")).await
+        } else if self.listing {
+            self.listing(&file, segments.is_empty()).await
+        } else {
+            not_found()
+        }
+    }
+
+    /// The existing file or directory for the path, never outside of the root.
+    async fn resolve(&self, segments: &[String]) -> Option<PathBuf> {
+        let path = segments
+            .iter()
+            .fold(self.root.clone(), |path, segment| path.join(segment));
+
+        // symlinks may still point elsewhere
+        fs::canonicalize(path)
+            .await
+            .ok()
+            .filter(|path| path.starts_with(&self.root))
+    }
+
+    async fn file(&self, path: &Path, range: Option<&str>) -> Response {
+        match read(path, range).await {
+            Ok(Some((status, content_range, body))) => {
+                let content_type = mime_guess::from_path(path).first_or_octet_stream();
+                let mut headers = vec![
+                    ("content-type", content_type.essence_str().to_string()),
+                    ("content-length", body.len().to_string()),
+                    ("accept-ranges", "bytes".to_string()),
+                ];
+                if let Some(content_range) = content_range {
+                    headers.push(("content-range", content_range));
+                }
+                (status, into_headers(headers), body)
+            }
+            Ok(None) => {
+                let length = fs::metadata(path)
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or_default();
+                text(
+                    416,
+                    "Range Not Satisfiable",
+                    &[("content-range", &format!("bytes */{length}"))],
+                )
+            }
+            Err(err) => {
+                output!("⚠️ WARNING: failed to read {}: {err}", path.display());
+                not_found()
+            }
+        }
+    }
+
+    async fn listing(&self, dir: &Path, root: bool) -> Response {
+        let mut entries = Vec::new();
+        if let Ok(mut dir) = fs::read_dir(dir).await {
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+                entries.push((!is_dir, entry.file_name().to_string_lossy().to_string()));
+            }
+        }
+        // directories first
+        entries.sort();
+
+        let title = match dir.strip_prefix(&self.root) {
+            Ok(path) => format!("/{}", path.to_string_lossy()),
+            Err(_) => "/".to_string(),
+        };
+        let mut links = if root {
+            String::new()
+        } else {
+            "<li><a href=\"../\">../</a></li>\n".to_string()
+        };
+        for (is_file, name) in entries {
+            let slash = if is_file { "" } else { "/" };
+            links.push_str(&format!(
+                "<li><a href=\"{}{slash}\">{}{slash}</a></li>\n",
+                utf8_percent_encode(&name, SEGMENT),
+                escape_html(&name)
+            ));
+        }
+
+        let page = format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>Index of {title}</title></head>\n<body>\n\
+             <h1>Index of {title}</h1>\n<ul>\n{links}</ul>\n</body>\n</html>\n",
+            title = escape_html(&title)
+        );
+        (
+            200,
+            into_headers(vec![
+                ("content-type", "text/html; charset=utf-8".to_string()),
+                ("content-length", page.len().to_string()),
+            ]),
+            page.into_bytes(),
+        )
+    }
+}
+
+/// The decoded segments of the path, `None` if it tries to leave the root.
+fn segments(path: &str) -> Option<Vec<String>> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        match segment.as_ref() {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains(['/', '\\', '\0']) => return None,
+            segment => segments.push(segment.to_string()),
+        }
+    }
+    Some(segments)
+}
+
+fn has_extension(segments: &[String]) -> bool {
+    segments
+        .last()
+        .is_some_and(|segment| Path::new(segment).extension().is_some())
+}
+
+/// Reads the file, or the requested range of it. `None` when the range can't be satisfied.
+async fn read(
+    path: &Path,
+    range: Option<&str>,
+) -> std::io::Result<Option<(u16, Option<String>, Vec<u8>)>> {
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+
+    let Some(range) = range.and_then(|range| parse_range(range, length)) else {
+        let mut body = Vec::with_capacity(length as usize);
+        file.read_to_end(&mut body).await?;
+        return Ok(Some((200, None, body)));
+    };
+    let Some((start, end)) = range else {
+        return Ok(None);
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut body = Vec::with_capacity((end - start + 1) as usize);
+    file.take(end - start + 1).read_to_end(&mut body).await?;
+    Ok(Some((
+        206,
+        Some(format!("bytes {start}-{end}/{length}")),
+        body,
+    )))
+}
+
+/// Parses a single `bytes` range into inclusive offsets, the outer `None` ignores the header
+/// (e.g: multiple ranges) and the inner `None` means it is not satisfiable.
+fn parse_range(range: &str, length: u64) -> Option<Option<(u64, u64)>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        (Some(start), None) if end.is_empty() => (start, length.saturating_sub(1)),
+        (None, Some(suffix)) if start.is_empty() => {
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        _ => return None,
+    };
+
+    Some((range.0 < length).then_some(range))
+}
+
+fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| std::str::from_utf8(value).ok())
+}
+
+fn into_headers(headers: Vec<(&str, String)>) -> Headers {
+    headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.into_bytes()))
+        .collect()
+}
+
+fn text(status: u16, body: &str, headers: &[(&str, &str)]) -> Response {
+    let mut headers: Vec<(&str, String)> = headers
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    headers.push(("content-type", "text/plain; charset=utf-8".to_string()));
+    (status, into_headers(headers), body.as_bytes().to_vec())
+}
+
+fn not_found() -> Response {
+    text(404, "Not Found", &[])
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rusty-relay-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join(INDEX), "<h1>home</h1>").unwrap();
+        std::fs::write(root.join("assets/app.js"), "0123456789").unwrap();
+        std::fs::write(root.join("docs/a b.txt"), "a").unwrap();
+        root
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        super::header(&response.1, name)
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let root = site("respond");
+        let files = StaticFiles::new(root.to_str().unwrap(), false, false).unwrap();
+
+        let index = files.respond("GET", "", &vec![]).await;
+        assert_eq!(index.0, 200);
+        assert_eq!(index.2, b"<h1>home</h1>");
+        assert_eq!(header(&index, "content-type"), Some("text/html"));
+
+        let script = files.respond("GET", "assets/app.js", &vec![]).await;
+        assert_eq!(header(&script, "content-type"), Some("text/javascript"));
+
+        let head = files.respond("HEAD", "assets/app.js", &vec![]).await;
+        assert_eq!((head.0, head.2.len()), (200, 0));
+
+        let redirect = files.respond("GET", "docs", &vec![]).await;
+        assert_eq!(redirect.0, 301);
+        assert_eq!(header(&redirect, "location"), Some("docs/"));
+
+        // no listing and no spa fallback
+        assert_eq!(files.respond("GET", "docs/", &vec![]).await.0, 404);
+        assert_eq!(files.respond("GET", "orders/1", &vec![]).await.0, 404);
+        assert_eq!(files.respond("POST", "", &vec![]).await.0, 405);
+
+        // never outside of the root
+        assert_eq!(files.respond("GET", "../etc/passwd", &vec![]).await.0, 404);
+        assert_eq!(
+            files.respond("GET", "docs/%2E%2E/..%2Fx", &vec![]).await.0,
+            404
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listing_and_spa() {
+        let root = site("spa");
+        let files = StaticFiles::new(root.to_str().unwrap(), true, true).unwrap();
+
+        let listing = files.respond("GET", "docs/", &vec![]).await;
+        let page = String::from_utf8(listing.2).unwrap();
+        assert!(page.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+        assert!(page.contains("<a href=\"../\">"));
+
+        let fallback = files.respond("GET", "orders/1", &vec![]).await;
+        assert_eq!(
+            (fallback.0, fallback.2.as_slice()),
+            (200, &b"<h1>home</h1>"[..])
+        );
+        assert_eq!(
+            files.respond("GET", "assets/missing.js", &vec![]).await.0,
+            404
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let root = site("range");
+        let files = StaticFiles::new(root.to_str().unwrap(), false, false).unwrap();
+        let range = |range: &str| vec![("Range".to_string(), range.as_bytes().to_vec())];
+
+        let partial = files
+            .respond("GET", "assets/app.js", &range("bytes=2-4"))
+            .await;
+        assert_eq!(partial.0, 206);
+        assert_eq!(partial.2, b"234");
+        assert_eq!(header(&partial, "content-range"), Some("bytes 2-4/10"));
+
+        let suffix = files
+            .respond("GET", "assets/app.js", &range("bytes=-3"))
+            .await;
+        assert_eq!(suffix.2, b"789");
+        let open = files
+            .respond("GET", "assets/app.js", &range("bytes=8-"))
+            .await;
+        assert_eq!(open.2, b"89");
+
+        let unsatisfiable = files
+            .respond("GET", "assets/app.js", &range("bytes=20-"))
+            .await;
+        assert_eq!(unsatisfiable.0, 416);
+        assert_eq!(header(&unsatisfiable, "content-range"), Some("bytes */10"));
+
+        // multiple ranges are answered with the whole file
+        let multiple = files
+            .respond("GET", "assets/app.js", &range("bytes=0-1,4-5"))
+            .await;
+        assert_eq!((multiple.0, multiple.2.len()), (200, 10));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
-use anyhow::{Context, anyhow};
-use rustls::pki_types::pem::PemObject;
-use std::{fs, sync::Arc};
+use crate::cli;
+use anyhow::{Context, anyhow, bail};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls_platform_verifier::Verifier;
+use std::sync::Arc;
 use tokio_tungstenite::Connector;
 
 pub fn init() -> anyhow::Result<()> {
@@ -10,25 +12,76 @@ pub fn init() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn connector(ca_cert_path: &Option<String>) -> anyhow::Result<Option<Connector>> {
-    let Some(cert_path) = ca_cert_path.as_ref() else {
-        return Ok(None);
+/// Reads every certificate from the given CA bundles, these are trusted next to the system trust store.
+pub fn ca_certs(paths: &[String]) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut certs = Vec::new();
+    for path in paths {
+        let bundle = CertificateDer::pem_file_iter(path)
+            .and_then(|bundle| bundle.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read CA certificate at path: {}", path))?;
+
+        if bundle.is_empty() {
+            bail!("no CA certificates found at path: {}", path);
+        }
+        certs.extend(bundle);
+    }
+    Ok(certs)
+}
+
+pub fn http_client(args: &cli::Args) -> anyhow::Result<reqwest::Client> {
+    let ca_certs = ca_certs(&args.ca_cert)?
+        .iter()
+        .map(|cert| reqwest::Certificate::from_der(cert))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to parse CA certificate for reqwest http client")?;
+
+    let builder = reqwest::Client::builder()
+        .tls_backend_rustls()
+        .tls_certs_merge(ca_certs)
+        .tls_danger_accept_invalid_certs(args.insecure_skip_verify);
+
+    #[cfg(unix)]
+    let builder = match args
+        .target
+        .as_ref()
+        .and_then(|target| target.unix_socket.as_ref())
+    {
+        Some(socket) => builder.unix_socket(socket.as_path()),
+        None => builder,
     };
 
-    let ca_cert = fs::read(cert_path)
-        .with_context(|| format!("failed to read CA certificate at path: {}", cert_path))?;
+    builder
+        .build()
+        .context("failed to build reqwest http client")
+}
+
+pub fn connector(args: &cli::Args) -> anyhow::Result<Connector> {
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .context("no default crypto provider installed")?
+        .clone();
+    let verifier = Verifier::new_with_extra_roots(ca_certs(&args.ca_cert)?, provider)
+        .context("failed to load system trust store")?;
 
-    let pem = rustls::pki_types::CertificateDer::from_pem_slice(&ca_cert)
-        .context("CA certificate is not valid PEM")?;
+    let builder = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
 
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store
-        .add(pem)
-        .context("failed to add CA certificate to RootCertStore")?;
+    let config = match (args.client_cert.as_ref(), args.client_key.as_ref()) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| {
+                    format!("failed to read client certificate at path: {}", cert_path)
+                })?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .with_context(|| format!("failed to read client key at path: {}", key_path))?;
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+            builder
+                .with_client_auth_cert(certs, key)
+                .context("failed to configure client certificate")?
+        }
+        _ => builder.with_no_client_auth(),
+    };
 
-    Ok(Some(Connector::Rustls(Arc::new(config))))
+    Ok(Connector::Rustls(Arc::new(config)))
 }
@@ -0,0 +1,576 @@
+use crate::inspect;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::{
+    DefaultTerminal, Frame,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+};
+use rusty_relay_messages::Headers;
+use std::{
+    io::Write,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+/// Older requests and log lines are dropped, to bound memory of a long running client.
+const MAX_EXCHANGES: usize = 1000;
+const MAX_LOG_LINES: usize = 500;
+
+/// Bodies are cut off in the details pane, they can be huge.
+const MAX_BODY_PREVIEW: usize = 64 * 1024;
+
+static EVENTS: OnceLock<UnboundedSender<Event>> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum Event {
+    Output(String),
+    Connected {
+        webhook_url: String,
+        proxy_url: String,
+    },
+    Disconnected(String),
+    Exchange(Exchange),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Proxy,
+    Webhook,
+}
+
+/// A request as received from the server, kept to show and replay it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub kind: Kind,
+    pub method: String,
+    /// Path relative to the target, still percent-encoded
+    pub path: Option<String>,
+    pub query: Option<String>,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+/// A request handled by the client and how it ended.
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub request: Request,
+    pub response: Option<(u16, Headers, Vec<u8>)>,
+    pub error: Option<String>,
+    pub latency: Duration,
+}
+
+/// Starts collecting the output and requests of the client for the terminal UI.
+pub fn init() -> UnboundedReceiver<Event> {
+    let (sender, receiver) = unbounded_channel();
+    let _ = EVENTS.set(sender);
+    receiver
+}
+
+pub fn enabled() -> bool {
+    EVENTS.get().is_some()
+}
+
+pub fn report(event: Event) {
+    if let Some(events) = EVENTS.get() {
+        let _ = events.send(event);
+    }
+}
+
+/// Prints the line, or adds it to the log when the terminal UI is running.
+pub fn output(line: String) {
+    if enabled() {
+        report(Event::Output(line));
+    } else {
+        println!("{line}");
+    }
+}
+
+/// Records the request (if the terminal UI is running) to report it with its outcome later.
+pub fn record(
+    kind: Kind,
+    method: &str,
+    path: Option<&str>,
+    query: Option<&str>,
+    headers: &Headers,
+    body: &[u8],
+) -> Option<(Request, Instant)> {
+    enabled().then(|| {
+        let request = Request {
+            kind,
+            method: method.to_string(),
+            path: path.map(str::to_string),
+            query: query.map(str::to_string),
+            headers: headers.clone(),
+            body: body.to_vec(),
+        };
+        (request, Instant::now())
+    })
+}
+
+pub fn report_exchange(
+    recorded: Option<(Request, Instant)>,
+    response: Option<(u16, Headers, Vec<u8>)>,
+    error: Option<String>,
+) {
+    if let Some((request, started)) = recorded {
+        report(Event::Exchange(Exchange {
+            request,
+            response,
+            error,
+            latency: started.elapsed(),
+        }));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+    Connecting,
+    Connected,
+    Disconnected(String),
+}
+
+#[derive(Debug)]
+pub struct App {
+    server: String,
+    upstream: String,
+    status: Status,
+    webhook_url: String,
+    proxy_url: String,
+    exchanges: Vec<Exchange>,
+    list: ListState,
+    /// Keep the newest request selected as requests come in
+    follow: bool,
+    scroll: u16,
+    log: Vec<String>,
+    replays: UnboundedSender<Request>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(server: &str, upstream: &str, replays: UnboundedSender<Request>) -> Self {
+        Self {
+            server: server.to_string(),
+            upstream: upstream.to_string(),
+            status: Status::Connecting,
+            webhook_url: String::new(),
+            proxy_url: String::new(),
+            exchanges: Vec::new(),
+            list: ListState::default(),
+            follow: true,
+            scroll: 0,
+            log: Vec::new(),
+            replays,
+            quit: false,
+        }
+    }
+
+    /// Runs until the user quits, the terminal is restored afterwards.
+    pub async fn run(mut self, mut events: UnboundedReceiver<Event>) -> anyhow::Result<()> {
+        let mut terminal = ratatui::init();
+        let result = self.run_in(&mut terminal, &mut events).await;
+        ratatui::restore();
+        result
+    }
+
+    async fn run_in(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        events: &mut UnboundedReceiver<Event>,
+    ) -> anyhow::Result<()> {
+        let mut input = EventStream::new();
+
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            tokio::select! {
+                Some(input) = input.next() => {
+                    if let TermEvent::Key(key) = input? {
+                        if key.kind == KeyEventKind::Press {
+                            self.on_key(key.code, key.modifiers);
+                        }
+                    }
+                }
+                Some(event) = events.recv() => self.on_event(event),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Output(output) => {
+                self.log.extend(output.lines().map(str::to_string));
+                let overflow = self.log.len().saturating_sub(MAX_LOG_LINES);
+                self.log.drain(..overflow);
+            }
+            Event::Connected {
+                webhook_url,
+                proxy_url,
+            } => {
+                self.status = Status::Connected;
+                self.webhook_url = webhook_url;
+                self.proxy_url = proxy_url;
+            }
+            Event::Disconnected(reason) => self.status = Status::Disconnected(reason),
+            Event::Exchange(exchange) => {
+                self.exchanges.push(exchange);
+                if self.exchanges.len() > MAX_EXCHANGES {
+                    self.exchanges.remove(0);
+                    if let Some(selected) = self.list.selected() {
+                        self.list.select(Some(selected.saturating_sub(1)));
+                    }
+                }
+                if self.follow {
+                    self.list.select(Some(self.exchanges.len() - 1));
+                    self.scroll = 0;
+                }
+            }
+        }
+    }
+
+    fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.select(1),
+            KeyCode::Up | KeyCode::Char('k') => self.select(-1),
+            KeyCode::Home | KeyCode::Char('g') => self.select(isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => self.select(isize::MAX),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Char('r') => {
+                if let Some(exchange) = self.selected() {
+                    let request = exchange.request.clone();
+                    self.log.push(format!(
+                        "🔁 replaying {} {}",
+                        request.method,
+                        display_path(&request)
+                    ));
+                    let _ = self.replays.send(request);
+                }
+            }
+            KeyCode::Char('p') => self.copy(self.proxy_url.clone()),
+            KeyCode::Char('w') => self.copy(self.webhook_url.clone()),
+            KeyCode::Char('y') => {
+                if let Some(url) = self.selected().map(|exchange| self.url(&exchange.request)) {
+                    self.copy(url);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn select(&mut self, delta: isize) {
+        if self.exchanges.is_empty() {
+            return;
+        }
+        let last = self.exchanges.len() - 1;
+        let current = self.list.selected().unwrap_or(last);
+        let selected = current.saturating_add_signed(delta).min(last);
+
+        self.list.select(Some(selected));
+        self.follow = selected == last;
+        self.scroll = 0;
+    }
+
+    fn selected(&self) -> Option<&Exchange> {
+        self.list
+            .selected()
+            .and_then(|selected| self.exchanges.get(selected))
+    }
+
+    /// The public url of the request.
+    fn url(&self, request: &Request) -> String {
+        match request.kind {
+            Kind::Webhook => self.webhook_url.clone(),
+            Kind::Proxy => format!("{}{}", self.proxy_url, display_path(request)),
+        }
+    }
+
+    /// Copies to the clipboard of the terminal (OSC 52), which also works over ssh.
+    fn copy(&mut self, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        let mut stdout = std::io::stdout();
+        let copied = write!(stdout, "\x1b]52;c;{}\x07", BASE64_STANDARD.encode(&text))
+            .and_then(|_| stdout.flush());
+        self.log.push(match copied {
+            Ok(_) => format!("📋 copied: {text}"),
+            Err(err) => format!("⚠️ WARNING: failed to copy: {err}"),
+        });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, main, log, help] = Layout::vertical([
+            Constraint::Length(5),
+            Constraint::Min(6),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [requests, details] =
+            Layout::horizontal([Constraint::Percentage(45), Constraint::Percentage(55)])
+                .areas(main);
+
+        self.draw_header(frame, header);
+        self.draw_requests(frame, requests);
+        self.draw_details(frame, details);
+
+        let lines = log.height.saturating_sub(2) as usize;
+        let tail = &self.log[self.log.len().saturating_sub(lines)..];
+        frame.render_widget(
+            Paragraph::new(
+                tail.iter()
+                    .map(|line| Line::raw(line.as_str()))
+                    .collect::<Vec<_>>(),
+            )
+            .block(Block::bordered().title(" Log ")),
+            log,
+        );
+
+        frame.render_widget(
+            Paragraph::new(
+                " q quit  ↑/↓ select  PgUp/PgDn scroll  r replay  p copy proxy url  w copy webhook url  y copy request url",
+            )
+            .dark_gray(),
+            help,
+        );
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let status = match &self.status {
+            Status::Connecting => Span::raw("◌ connecting").yellow(),
+            Status::Connected => Span::raw("● connected").green(),
+            Status::Disconnected(reason) => Span::raw(format!("○ disconnected: {reason}")).red(),
+        };
+        let field = |name: &'static str, value: &str| {
+            Line::from(vec![
+                Span::raw(format!("{name:<9}")).dark_gray(),
+                Span::raw(if value.is_empty() { "-" } else { value }.to_string()),
+            ])
+        };
+
+        let text = vec![
+            Line::from(vec![
+                Span::raw(format!("{:<9}", "server")).dark_gray(),
+                Span::raw(format!("{}  ", self.server)),
+                status,
+            ]),
+            field("proxy", &self.proxy_url),
+            field("webhook", &self.webhook_url),
+        ];
+        frame.render_widget(
+            Paragraph::new(text).block(
+                Block::bordered()
+                    .title(format!(" 🦀 rusty-relay → {} ", self.upstream))
+                    .bold(),
+            ),
+            area,
+        );
+    }
+
+    fn draw_requests(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .exchanges
+            .iter()
+            .map(|exchange| {
+                let request = &exchange.request;
+                let kind = match request.kind {
+                    Kind::Proxy => "PROXY",
+                    Kind::Webhook => "HOOK ",
+                };
+                let status = match (&exchange.response, &exchange.error) {
+                    (Some((status, ..)), _) => {
+                        Span::styled(status.to_string(), Style::new().fg(status_color(*status)))
+                    }
+                    (None, Some(_)) => Span::raw("ERR").red(),
+                    (None, None) => Span::raw("-").dark_gray(),
+                };
+
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{kind} ")).dark_gray(),
+                    Span::raw(format!("{:<7}", request.method)).bold(),
+                    status,
+                    Span::raw(format!(" {:>6} ", latency(exchange.latency))).dark_gray(),
+                    Span::raw(display_path(request)),
+                ]))
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" Requests ({}) ", self.exchanges.len())))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.list);
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let text = match self.selected() {
+            Some(exchange) => details(exchange),
+            None => Text::raw("No requests yet").dark_gray(),
+        };
+        frame.render_widget(
+            Paragraph::new(text)
+                .block(Block::bordered().title(" Details "))
+                .wrap(Wrap { trim: false })
+                .scroll((self.scroll, 0)),
+            area,
+        );
+    }
+}
+
+fn details(exchange: &Exchange) -> Text<'static> {
+    let request = &exchange.request;
+    let mut lines = vec![Line::from(
+        format!("{} {}", request.method, display_path(request)).bold(),
+    )];
+    headers_and_body(&mut lines, &request.headers, &request.body);
+
+    lines.push(Line::raw(""));
+    match (&exchange.response, &exchange.error) {
+        (Some((status, headers, body)), _) => {
+            lines.push(Line::from(vec![
+                Span::styled(status.to_string(), Style::new().fg(status_color(*status))).bold(),
+                Span::raw(format!(" in {}", latency(exchange.latency))),
+            ]));
+            headers_and_body(&mut lines, headers, body);
+        }
+        (None, Some(error)) => lines.push(Line::from(error.clone().red())),
+        (None, None) => lines.push(Line::from("not forwarded to a target".dark_gray())),
+    }
+
+    Text::from(lines)
+}
+
+fn headers_and_body(lines: &mut Vec<Line<'static>>, headers: &Headers, body: &[u8]) {
+    for (name, value) in headers {
+        lines.push(Line::from(vec![
+            Span::raw(format!("{name}: ")).cyan(),
+            Span::raw(String::from_utf8_lossy(value).to_string()),
+        ]));
+    }
+
+    let content_type = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| String::from_utf8_lossy(value).to_ascii_lowercase());
+    let body = match body.len() > MAX_BODY_PREVIEW {
+        true => format!("<{} bytes, too large to show>", body.len()),
+        false => inspect::format_body(content_type.as_deref(), body),
+    };
+    if !body.is_empty() {
+        lines.push(Line::raw(""));
+        lines.extend(body.lines().map(|line| Line::raw(line.to_string())));
+    }
+}
+
+fn display_path(request: &Request) -> String {
+    let mut path = match request.kind {
+        Kind::Webhook => String::new(),
+        Kind::Proxy => format!("/{}", request.path.as_deref().unwrap_or_default()),
+    };
+    if let Some(query) = &request.query {
+        path.push_str(&format!("?{query}"));
+    }
+    path
+}
+
+fn latency(latency: Duration) -> String {
+    match latency.as_millis() {
+        millis if millis < 1000 => format!("{millis}ms"),
+        _ => format!("{:.1}s", latency.as_secs_f64()),
+    }
+}
+
+fn status_color(status: u16) -> Color {
+    match status {
+        200..=299 => Color::Green,
+        300..=399 => Color::Cyan,
+        400..=499 => Color::Yellow,
+        _ => Color::Red,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::{Terminal, backend::TestBackend};
+
+    fn exchange(path: &str, status: u16) -> Exchange {
+        Exchange {
+            request: Request {
+                kind: Kind::Proxy,
+                method: "GET".to_string(),
+                path: Some(path.to_string()),
+                query: Some("page=2".to_string()),
+                headers: vec![("accept".to_string(), b"application/json".to_vec())],
+                body: Vec::new(),
+            },
+            response: Some((
+                status,
+                vec![("content-type".to_string(), b"application/json".to_vec())],
+                br#"{"id":1}"#.to_vec(),
+            )),
+            error: None,
+            latency: Duration::from_millis(12),
+        }
+    }
+
+    #[test]
+    fn test_app() {
+        let (replays, mut replayed) = unbounded_channel();
+        let mut app = App::new("relay.example.com", "http://localhost:3000", replays);
+        app.on_event(Event::Connected {
+            webhook_url: "https://relay.example.com/webhook/shop".to_string(),
+            proxy_url: "https://relay.example.com/proxy/shop".to_string(),
+        });
+        app.on_event(Event::Exchange(exchange("api/users", 200)));
+        app.on_event(Event::Exchange(exchange("api/orders", 502)));
+        app.on_event(Event::Output("connected to relay".to_string()));
+
+        // the newest request is followed until another one is selected
+        assert_eq!(app.list.selected(), Some(1));
+        app.on_key(KeyCode::Up, KeyModifiers::NONE);
+        app.on_event(Event::Exchange(exchange("api/cart", 200)));
+        assert_eq!(app.list.selected(), Some(0));
+        assert_eq!(
+            app.url(&app.selected().unwrap().request),
+            "https://relay.example.com/proxy/shop/api/users?page=2"
+        );
+
+        app.on_key(KeyCode::Char('r'), KeyModifiers::NONE);
+        assert_eq!(
+            replayed.try_recv().unwrap().path.as_deref(),
+            Some("api/users")
+        );
+
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect::<String>();
+        for expected in [
+            "● connected",
+            "https://relay.example.com/proxy/shop",
+            "GET    200",
+            "/api/orders?page=2",
+            "\"id\": 1",
+            "connected to relay",
+        ] {
+            assert!(screen.contains(expected), "{expected} not on screen");
+        }
+
+        app.on_key(KeyCode::Char('q'), KeyModifiers::NONE);
+        assert!(app.quit);
+    }
+}
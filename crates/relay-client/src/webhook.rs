@@ -1,39 +1,93 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{str::FromStr, sync::RwLock};
 
+use crate::{
+    exec::Exec,
+    inspect::Inspector,
+    rewrite::{Direction, Rules},
+    tui::{self, Kind},
+};
 use anyhow::Context;
 use reqwest::{
     Client, Method,
     header::{HeaderMap, HeaderName, HeaderValue},
 };
+use rusty_relay_messages::Headers;
 
 #[derive(Debug)]
 pub struct WebhookHandler<'a> {
-    target: &'a str,
+    target: Option<&'a str>,
+    exec: Option<Exec>,
+    inspector: Option<Inspector>,
     http_client: Client,
+    rules: &'a Rules,
+    webhook_url: RwLock<String>,
 }
 
 impl<'a> WebhookHandler<'a> {
-    pub fn new(target: &'a str, http_client: Client) -> Self {
+    pub fn new(
+        target: Option<&'a str>,
+        exec: Option<Exec>,
+        inspector: Option<Inspector>,
+        http_client: Client,
+        rules: &'a Rules,
+    ) -> Self {
         Self {
             target,
+            exec,
+            inspector,
             http_client,
+            rules,
+            webhook_url: RwLock::default(),
         }
     }
 
     pub async fn handle(
         &self,
         method: String,
-        headers: HashMap<String, String>,
-        body: Vec<u8>,
+        mut headers: Headers,
+        mut body: Vec<u8>,
     ) -> anyhow::Result<()> {
+        if let Some(inspector) = &self.inspector {
+            inspector.inspect(&method, &headers, &body);
+        }
+        let recorded = tui::record(Kind::Webhook, &method, None, None, &headers, &body);
+
+        let webhook_url = self
+            .webhook_url
+            .read()
+            .expect("lock should not be poisoned")
+            .clone();
+        self.rules.apply(
+            Direction::Request,
+            "",
+            &mut headers,
+            &mut body,
+            &webhook_url,
+        );
+
+        if let Some(exec) = &self.exec {
+            exec.run(method, headers, body);
+            tui::report_exchange(recorded, None, None);
+            return Ok(());
+        }
+        let Some(target) = self.target else {
+            if self.inspector.is_none() {
+                output!(
+                    "⚠️ WARNING: received webhook ({method}) but there is no target to forward it to"
+                );
+            }
+            tui::report_exchange(recorded, None, None);
+            return Ok(());
+        };
+
         let mut request_headers = HeaderMap::with_capacity(headers.len());
         for (k, v) in headers {
-            request_headers.insert(
+            request_headers.append(
                 k.parse::<HeaderName>().with_context(|| {
                     format!("failed to parse key: {} as header name for webhook", &k)
                 })?,
-                v.parse::<HeaderValue>().with_context(|| {
-                    format!("failed to parse value: {} as header value for webhook", &v)
+                HeaderValue::from_bytes(&v).with_context(|| {
+                    format!("failed to parse value of header: {} for webhook", &k)
                 })?,
             );
         }
@@ -43,42 +97,58 @@ impl<'a> WebhookHandler<'a> {
             .request(
                 Method::from_str(&method)
                     .with_context(|| format!("failed to parse http method: {}", &method))?,
-                self.target,
+                target,
             )
             .headers(request_headers)
             .body(body)
             .send()
-            .await
-            .map_err(|err| {
-                println!(
-                    "⚠️ WARNING: request ({method}) to {} failed: {err}",
-                    self.target
-                )
-            });
+            .await;
 
-        if let Ok(res) = response {
-            println!(
-                "➡️ forwarded webhook ({}) to {}, got {}",
-                method,
-                self.target,
-                res.status()
-            );
+        let res = match response {
+            Ok(res) => res,
+            Err(err) => {
+                output!("⚠️ WARNING: request ({method}) to {target} failed: {err}");
+                tui::report_exchange(recorded, None, Some(err.to_string()));
+                return Ok(());
+            }
+        };
 
-            if res.status().is_client_error() || res.status().is_server_error() {
-                println!(
-                    "❌ ERROR:\n{}",
-                    res.text()
-                        .await
-                        .context("failed to parse webhook response body text")?
-                );
+        let status = res.status();
+        output!("➡️ forwarded webhook ({method}) to {target}, got {status}");
+
+        let response_headers: Headers = res
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect();
+        let response_body = match res.bytes().await {
+            Ok(body) => body.to_vec(),
+            Err(err) => {
+                output!("⚠️ WARNING: failed to read response of webhook ({method}): {err}");
+                tui::report_exchange(recorded, None, Some(err.to_string()));
+                return Ok(());
             }
+        };
+
+        if status.is_client_error() || status.is_server_error() {
+            output!("❌ ERROR:\n{}", String::from_utf8_lossy(&response_body));
         }
+        tui::report_exchange(
+            recorded,
+            Some((status.as_u16(), response_headers, response_body)),
+            None,
+        );
 
         Ok(())
     }
 
-    pub fn print_url(&self, client_id: &str, protocol: &str, server: &str) {
+    pub fn print_url(&self, client_id: &str, protocol: &str, server: &str) -> String {
         let webhook_url = format!("{}{}/webhook/{}", protocol, server, client_id);
-        println!("✅ You can send webhooks to: {webhook_url}");
+        output!("✅ You can send webhooks to: {webhook_url}");
+        *self
+            .webhook_url
+            .write()
+            .expect("lock should not be poisoned") = webhook_url.clone();
+        webhook_url
     }
 }
@@ -1,10 +1,22 @@
-use crate::{cli, proxy::ProxyHandler, tls, webhook::WebhookHandler};
+use crate::{
+    cli, dialer,
+    proxy::ProxyHandler,
+    tls,
+    tui::{self, Event, Kind, Request},
+    webhook::WebhookHandler,
+};
 use anyhow::Context;
-use futures_util::{SinkExt, StreamExt};
-use rusty_relay_messages::RelayMessage;
+use futures_util::{
+    FutureExt, SinkExt, StreamExt,
+    future::{AbortHandle, Abortable},
+    stream::FuturesUnordered,
+};
+use rusty_relay_messages::{CLIENT_OPTIONS_HEADER, Headers, RelayMessage};
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_tungstenite::{
-    connect_async_tls_with_config,
-    tungstenite::{self, Message, Utf8Bytes, client::IntoClientRequest},
+    client_async_tls_with_config,
+    tungstenite::{self, Message, client::IntoClientRequest},
 };
 
 #[derive(Debug)]
@@ -27,10 +39,14 @@ impl<'a> Client<'a> {
         }
     }
 
-    pub async fn connect_blocking(&self) -> anyhow::Result<()> {
+    /// Relays until the connection closes, `replays` are requests to handle again (from the
+    /// terminal UI) of which the response is not sent to the server.
+    pub async fn connect_blocking(
+        &self,
+        mut replays: Option<UnboundedReceiver<Request>>,
+    ) -> anyhow::Result<()> {
         let insecure = self.cli_args.insecure;
-        let connect = connect_async_tls_with_config;
-        let tls_connector = tls::connector(&self.cli_args.ca_cert)?;
+        let tls_connector = tls::connector(self.cli_args)?;
         let token = &self.cli_args.token;
 
         let ws_proto = if insecure { "ws://" } else { "wss://" };
@@ -46,27 +62,123 @@ impl<'a> Client<'a> {
                 .with_context(|| format!("failed to parse token into header value: {}", token))?,
         );
 
-        match connect(request, None, false, tls_connector).await {
+        let options = serde_json::to_string(&self.cli_args.client_options())
+            .context("failed to serialize client options")?;
+        request.headers_mut().insert(
+            CLIENT_OPTIONS_HEADER,
+            options
+                .parse()
+                .context("failed to parse client options into header value")?,
+        );
+
+        let host = request
+            .uri()
+            .host()
+            .context("failed to get host from websocket url")?
+            .to_string();
+        let port = request
+            .uri()
+            .port_u16()
+            .unwrap_or(if insecure { 80 } else { 443 });
+        let proxy = dialer::proxy_url(self.cli_args.proxy.as_deref(), !insecure, &host)?;
+        let stream = dialer::connect(&host, port, proxy.as_ref()).await?;
+
+        match client_async_tls_with_config(request, stream, None, Some(tls_connector)).await {
             Ok(ws_stream) => {
                 let (mut write, mut read) = ws_stream.0.split();
-                while let Some(msg) = read.next().await {
-                    if let Ok(Message::Text(message)) = msg {
-                        if let Some(response) = self.handle_message(message).await? {
-                            write
-                                .send(Message::Text(
-                                    serde_json::to_string(&response)
-                                        .context("failed to serialize RelayMessage")?
-                                        .into(),
-                                ))
-                                .await
-                                .context("failed to write to websocket")?;
+
+                // proxy requests run concurrently, so they can be cancelled by the server
+                let mut in_flight = FuturesUnordered::new();
+                let mut abort_handles = HashMap::new();
+                // and so do webhooks, a slow target doesn't hold up the proxy requests
+                let mut webhooks = FuturesUnordered::new();
+                let mut replaying = FuturesUnordered::new();
+
+                loop {
+                    tokio::select! {
+                        msg = read.next() => {
+                            let Some(msg) = msg else {
+                                break;
+                            };
+                            let message = match msg {
+                                Ok(Message::Text(message)) => message,
+                                Ok(Message::Close(Some(frame))) if !frame.reason.is_empty() => {
+                                    output!("❌ ERROR: {}", frame.reason);
+                                    continue;
+                                }
+                                _ => continue,
+                            };
+
+                            match serde_json::from_slice::<RelayMessage>(message.as_bytes())
+                                .context("failed to deserialize into RelayMessage from bytes")?
+                            {
+                                RelayMessage::ProxyRequest {
+                                    request_id,
+                                    path,
+                                    query,
+                                    method,
+                                    headers,
+                                    body,
+                                } => {
+                                    let (abort_handle, registration) = AbortHandle::new_pair();
+                                    abort_handles.insert(request_id.clone(), abort_handle);
+
+                                    let proxy_response = self.proxy_handler.handle(
+                                        request_id.clone(),
+                                        path,
+                                        query,
+                                        method,
+                                        headers,
+                                        body,
+                                    );
+                                    in_flight.push(
+                                        Abortable::new(proxy_response, registration)
+                                            .map(move |result| (request_id, result)),
+                                    );
+                                }
+                                RelayMessage::Webhook {
+                                    method,
+                                    headers,
+                                    body,
+                                } => {
+                                    webhooks.push(self.webhook(method, headers, body));
+                                }
+                                RelayMessage::CancelRequest { request_id } => {
+                                    if let Some(handle) = abort_handles.remove(&request_id) {
+                                        handle.abort();
+                                        output!("🚫 cancelled proxy request: {request_id}");
+                                    }
+                                }
+                                RelayMessage::ClientId(client_id) => self.connected(&client_id),
+                                _ => {}
+                            }
+                        }
+                        Some(request) = next_replay(&mut replays) => {
+                            replaying.push(self.replay(request));
+                        }
+                        Some(()) = webhooks.next() => {}
+                        Some(()) = replaying.next() => {}
+                        Some((request_id, result)) = in_flight.next() => {
+                            abort_handles.remove(&request_id);
+
+                            // a cancelled request has no response
+                            if let Ok(response) = result {
+                                write
+                                    .send(Message::Text(
+                                        serde_json::to_string(&response)
+                                            .context("failed to serialize RelayMessage")?
+                                            .into(),
+                                    ))
+                                    .await
+                                    .context("failed to write to websocket")?;
+                            }
                         }
                     }
                 }
             }
             Err(tungstenite::Error::Http(response)) => {
                 if let Some(body) = response.body() {
-                    println!("❌ ERROR: {}", String::from_utf8_lossy(body));
+                    output!("❌ ERROR: {}", String::from_utf8_lossy(body));
                 }
             }
             Err(err) => return Err(err.into()),
@@ -75,44 +187,57 @@ impl<'a> Client<'a> {
         Ok(())
     }
 
-    async fn handle_message(&self, message: Utf8Bytes) -> anyhow::Result<Option<RelayMessage>> {
-        match serde_json::from_slice::<RelayMessage>(message.as_bytes())
-            .context("failed to deserialize into RelayMessage from bytes")?
-        {
-            RelayMessage::Webhook {
-                method,
-                body,
-                headers,
-            } => {
-                self.webhook_handler.handle(method, headers, body).await?;
-            }
-            RelayMessage::ClientId(ref client_id) => {
-                let insecure = self.cli_args.insecure;
-                let http_proto = if insecure { "http://" } else { "https://" };
+    fn connected(&self, client_id: &str) {
+        let insecure = self.cli_args.insecure;
+        let http_proto = if insecure { "http://" } else { "https://" };
+
+        let webhook_url =
+            self.webhook_handler
+                .print_url(client_id, http_proto, &self.cli_args.server);
+        let proxy_url = self
+            .proxy_handler
+            .print_url(client_id, http_proto, &self.cli_args.server);
+        tui::report(Event::Connected {
+            webhook_url,
+            proxy_url,
+        });
+    }
+
+    /// Handles a webhook, a failure is only logged so it doesn't end the connection.
+    async fn webhook(&self, method: String, headers: Headers, body: Vec<u8>) {
+        if let Err(err) = self.webhook_handler.handle(method, headers, body).await {
+            output!("⚠️ WARNING: failed to handle webhook: {err:#}");
+        }
+    }
+
+    async fn replay(&self, request: Request) {
+        let Request {
+            kind,
+            method,
+            path,
+            query,
+            headers,
+            body,
+        } = request;
 
-                self.webhook_handler
-                    .print_url(client_id, http_proto, &self.cli_args.server);
+        match kind {
+            Kind::Proxy => {
                 self.proxy_handler
-                    .print_url(client_id, http_proto, &self.cli_args.server);
+                    .handle("replay".to_string(), path, query, method, headers, body)
+                    .await;
             }
-            RelayMessage::ProxyRequest {
-                request_id,
-                path,
-                query,
-                method,
-                headers,
-                body,
-            } => {
-                let proxy_response = self
-                    .proxy_handler
-                    .handle(request_id, path, query, method, headers, body)
-                    .await?;
-
-                return Ok(proxy_response);
+            Kind::Webhook => {
+                if let Err(err) = self.webhook_handler.handle(method, headers, body).await {
+                    output!("⚠️ WARNING: failed to replay webhook: {err:#}");
+                }
             }
-            _ => {}
         }
+    }
+}
 
-        Ok(None)
+async fn next_replay(replays: &mut Option<UnboundedReceiver<Request>>) -> Option<Request> {
+    match replays {
+        Some(replays) => replays.recv().await,
+        None => std::future::pending().await,
     }
 }
@@ -1,27 +1,160 @@
-use std::collections::HashMap;
+use std::{fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+/// Header in which the client sends its (JSON encoded) [`ClientOptions`] when connecting.
+pub const CLIENT_OPTIONS_HEADER: &str = "RUSTY-RELAY-OPTIONS";
+
+/// Header names with their raw values, in order and including repeated names e.g: `Set-Cookie`.
+pub type Headers = Vec<(String, Vec<u8>)>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RelayMessage {
     Webhook {
         method: String,
-        headers: HashMap<String, String>,
+        headers: Headers,
         body: Vec<u8>,
     },
     ClientId(String),
     ProxyRequest {
         request_id: String,
         method: String,
-        headers: HashMap<String, String>,
+        headers: Headers,
         body: Vec<u8>,
+        /// Path relative to the target as received, still percent-encoded
         path: Option<String>,
+        /// Query string as received, without the leading `?`
         query: Option<String>,
     },
     ProxyResponse {
         request_id: String,
-        headers: HashMap<String, String>,
+        headers: Headers,
         body: Vec<u8>,
         status: u16,
     },
+    /// The client could not get a response from its local target
+    ProxyError {
+        request_id: String,
+        kind: ProxyErrorKind,
+        message: String,
+    },
+    /// The proxy request was abandoned (timeout or the caller went away)
+    CancelRequest {
+        request_id: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyErrorKind {
+    /// The local target refused the connection or failed while responding
+    TargetUnreachable,
+    /// The local target did not respond in time
+    TargetTimeout,
+    /// The request could not be sent to the local target e.g: an invalid header
+    InvalidRequest,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientOptions {
+    /// Connect under this name instead of a random id, clients with the same name share it
+    pub name: Option<String>,
+    pub protection: Option<Protection>,
+    pub webhook_signature: Option<WebhookSignature>,
+    /// Seconds to await a proxy response, the server default is used when absent
+    pub proxy_timeout: Option<u64>,
+    /// Proxy timeouts for specific routes, the first matching route is used
+    pub route_timeouts: Vec<RouteTimeout>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTimeout {
+    /// Path of the route, a trailing `*` matches anything e.g: /reports/*
+    pub path: String,
+    /// Seconds to await a proxy response
+    pub timeout: u64,
+}
+
+impl RouteTimeout {
+    pub fn matches(&self, path: &str) -> bool {
+        path_matches(&self.path, path)
+    }
+}
+
+/// Whether `path` matches the route `pattern`, a trailing `*` in the pattern matches anything.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_start_matches('/');
+    let path = path.trim_start_matches('/');
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+/// Restricts who can reach the public proxy url of a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Protection {
+    pub basic_auth: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
+    /// IP addresses or CIDR ranges e.g: 10.0.0.0/8
+    pub allow_ips: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Verifies the provider signature of incoming webhooks before they are relayed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookSignature {
+    pub provider: SignatureProvider,
+    pub secret: String,
+    /// Header containing the signature, only used by [`SignatureProvider::Hmac`] (default: X-Signature)
+    #[serde(default)]
+    pub header: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureProvider {
+    /// `X-Hub-Signature-256` header
+    Github,
+    /// `Stripe-Signature` header
+    Stripe,
+    /// `X-Slack-Signature` and `X-Slack-Request-Timestamp` headers
+    Slack,
+    /// Hex encoded HMAC-SHA256 of the body
+    Hmac,
+}
+
+impl FromStr for SignatureProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "github" => Ok(Self::Github),
+            "stripe" => Ok(Self::Stripe),
+            "slack" => Ok(Self::Slack),
+            "hmac" => Ok(Self::Hmac),
+            _ => Err(format!(
+                "unknown signature provider: {s} (use github, stripe, slack or hmac)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SignatureProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let provider = match self {
+            Self::Github => "github",
+            Self::Stripe => "stripe",
+            Self::Slack => "slack",
+            Self::Hmac => "hmac",
+        };
+        f.write_str(provider)
+    }
 }
@@ -0,0 +1,194 @@
+use crate::error::HttpError;
+use axum::http::{HeaderMap, header::AUTHORIZATION};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ipnet::IpNet;
+use rusty_relay_messages::{BasicAuth, Protection};
+use std::net::IpAddr;
+
+/// Enforces the [`Protection`] a client requested for its proxy url.
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    basic_auth: Option<BasicAuth>,
+    bearer_token: Option<String>,
+    allow_ips: Vec<IpNet>,
+}
+
+impl TryFrom<Protection> for AccessPolicy {
+    type Error = HttpError;
+
+    fn try_from(protection: Protection) -> Result<Self, Self::Error> {
+        let allow_ips = protection
+            .allow_ips
+            .iter()
+            .map(|ip| {
+                parse_ip_net(ip).ok_or_else(|| {
+                    HttpError::BadRequest(format!("Invalid IP address or CIDR range: {ip}"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            basic_auth: protection.basic_auth,
+            bearer_token: protection.bearer_token,
+            allow_ips,
+        })
+    }
+}
+
+impl AccessPolicy {
+    /// Checks whether the caller may access the proxy url, the credentials meant for
+    /// the relay are removed from `headers` so they don't reach the target.
+    pub fn check(&self, headers: &mut HeaderMap, caller_ip: IpAddr) -> Result<(), HttpError> {
+        if !self.allow_ips.is_empty() && !self.allow_ips.iter().any(|net| net.contains(&caller_ip))
+        {
+            return Err(HttpError::Forbidden(format!(
+                "Access denied for IP address: {caller_ip}"
+            )));
+        }
+
+        if self.basic_auth.is_none() && self.bearer_token.is_none() {
+            return Ok(());
+        }
+
+        let authorized = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| self.is_authorized(value));
+
+        if authorized {
+            headers.remove(AUTHORIZATION);
+            Ok(())
+        } else if self.basic_auth.is_some() {
+            Err(HttpError::BasicAuthRequired(
+                "Authentication required".to_string(),
+            ))
+        } else {
+            Err(HttpError::Unauthorized(
+                "Bearer token is missing or invalid".to_string(),
+            ))
+        }
+    }
+
+    fn is_authorized(&self, authorization: &str) -> bool {
+        let Some((scheme, credentials)) = authorization.split_once(' ') else {
+            return false;
+        };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some(basic_auth) = self.basic_auth.as_ref() else {
+                return false;
+            };
+            let expected = format!("{}:{}", basic_auth.username, basic_auth.password);
+
+            BASE64_STANDARD
+                .decode(credentials)
+                .is_ok_and(|decoded| constant_time_eq(&decoded, expected.as_bytes()))
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            self.bearer_token
+                .as_ref()
+                .is_some_and(|token| constant_time_eq(credentials.as_bytes(), token.as_bytes()))
+        } else {
+            false
+        }
+    }
+}
+
+pub fn parse_ip_net(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(protection: Protection) -> AccessPolicy {
+        AccessPolicy::try_from(protection).unwrap()
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let policy = policy(Protection {
+            basic_auth: Some(BasicAuth {
+                username: "admin".to_string(),
+                password: "secret".to_string(),
+            }),
+            ..Default::default()
+        });
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let mut headers = authorization("Basic YWRtaW46c2VjcmV0");
+        assert!(policy.check(&mut headers, ip).is_ok());
+        assert!(headers.get(AUTHORIZATION).is_none());
+
+        let mut headers = authorization("Basic YWRtaW46d3Jvbmc=");
+        assert!(matches!(
+            policy.check(&mut headers, ip),
+            Err(HttpError::BasicAuthRequired(_))
+        ));
+        assert!(matches!(
+            policy.check(&mut HeaderMap::new(), ip),
+            Err(HttpError::BasicAuthRequired(_))
+        ));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let policy = policy(Protection {
+            bearer_token: Some("t0ken".to_string()),
+            ..Default::default()
+        });
+        let ip = "127.0.0.1".parse().unwrap();
+
+        assert!(policy.check(&mut authorization("Bearer t0ken"), ip).is_ok());
+        assert!(matches!(
+            policy.check(&mut authorization("Bearer t0ke"), ip),
+            Err(HttpError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_allow_ips() {
+        let policy = policy(Protection {
+            allow_ips: vec!["10.0.0.0/8".to_string(), "192.168.1.10".to_string()],
+            ..Default::default()
+        });
+        let mut headers = HeaderMap::new();
+
+        assert!(
+            policy
+                .check(&mut headers, "10.1.2.3".parse().unwrap())
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check(&mut headers, "192.168.1.10".parse().unwrap())
+                .is_ok()
+        );
+        assert!(matches!(
+            policy.check(&mut headers, "192.168.1.11".parse().unwrap()),
+            Err(HttpError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_allow_ips() {
+        let result = AccessPolicy::try_from(Protection {
+            allow_ips: vec!["10.0.0.0/33".to_string()],
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(HttpError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,545 @@
+use crate::{error::BoxError, tls, util::from_env_or_else};
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, process::Command, time};
+use tracing::{error, info, warn};
+
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+type Result<T> = std::result::Result<T, BoxError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Challenge {
+    Http01,
+    TlsAlpn01,
+    Dns01,
+}
+
+impl FromStr for Challenge {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "http-01" => Ok(Challenge::Http01),
+            "tls-alpn-01" => Ok(Challenge::TlsAlpn01),
+            "dns-01" => Ok(Challenge::Dns01),
+            _ => Err(format!("unsupported ACME challenge type: {s}")),
+        }
+    }
+}
+
+impl From<Challenge> for ChallengeType {
+    fn from(challenge: Challenge) -> Self {
+        match challenge {
+            Challenge::Http01 => ChallengeType::Http01,
+            Challenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+            Challenge::Dns01 => ChallengeType::Dns01,
+        }
+    }
+}
+
+/// Issues and renews the server certificate through an ACME directory (e.g. Let's Encrypt).
+pub struct Acme {
+    domain: String,
+    wildcard: bool,
+    contact: Option<String>,
+    directory: String,
+    ca_cert: Option<String>,
+    challenge: Challenge,
+    dns_hook: Option<String>,
+    cache_dir: PathBuf,
+    resolver: Arc<CertResolver>,
+    http_tokens: RwLock<HashMap<String, String>>,
+}
+
+impl Acme {
+    /// Returns `None` when `RUSTY_RELAY_ACME_DOMAIN` is not set.
+    pub fn from_env() -> Result<Option<Arc<Self>>> {
+        let Ok(domain) = std::env::var("RUSTY_RELAY_ACME_DOMAIN") else {
+            return Ok(None);
+        };
+
+        let wildcard = from_env_or_else("RUSTY_RELAY_ACME_WILDCARD", || false);
+        let challenge = std::env::var("RUSTY_RELAY_ACME_CHALLENGE")
+            .map(|c| c.parse::<Challenge>())
+            .unwrap_or(Ok(Challenge::TlsAlpn01))?;
+        let dns_hook = std::env::var("RUSTY_RELAY_ACME_DNS_HOOK").ok();
+
+        if wildcard && challenge != Challenge::Dns01 {
+            return Err("wildcard certificates require the dns-01 challenge".into());
+        }
+        if challenge == Challenge::Dns01 && dns_hook.is_none() {
+            return Err("the dns-01 challenge requires RUSTY_RELAY_ACME_DNS_HOOK".into());
+        }
+
+        Ok(Some(Arc::new(Self {
+            domain,
+            wildcard,
+            contact: std::env::var("RUSTY_RELAY_ACME_EMAIL").ok(),
+            directory: from_env_or_else("RUSTY_RELAY_ACME_DIRECTORY", || {
+                LetsEncrypt::Production.url().to_string()
+            }),
+            ca_cert: std::env::var("RUSTY_RELAY_ACME_CA_CERT").ok(),
+            challenge,
+            dns_hook,
+            cache_dir: PathBuf::from(from_env_or_else("RUSTY_RELAY_ACME_CACHE_DIR", || {
+                "./certs/acme".to_string()
+            })),
+            resolver: Arc::new(CertResolver::default()),
+            http_tokens: RwLock::new(HashMap::new()),
+        })))
+    }
+
+    /// Loads a cached certificate (if any), starts the renewal task and returns the TLS config.
+    pub async fn start(self: Arc<Self>) -> Result<RustlsConfig> {
+        fs::create_dir_all(&self.cache_dir).await?;
+
+        if let Some(certificate) = self.load_cached_certificate().await {
+            info!("🔐 loaded cached ACME certificate for {}", self.domain);
+            self.resolver.set_certificate(certificate);
+        }
+
+        let mut config = tls::server_config_builder()?.with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = tls::alpn_protocols();
+        config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+
+        if self.challenge == Challenge::Http01 {
+            tokio::spawn(self.clone().serve_http_challenges());
+        }
+        tokio::spawn(self.renew_loop());
+
+        Ok(RustlsConfig::from_config(Arc::new(config)))
+    }
+
+    async fn renew_loop(self: Arc<Self>) {
+        loop {
+            let wait = self
+                .resolver
+                .renew_at()
+                .and_then(|renew_at| renew_at.duration_since(SystemTime::now()).ok())
+                .unwrap_or_default();
+
+            if !wait.is_zero() {
+                time::sleep(wait.min(CHECK_INTERVAL)).await;
+                continue;
+            }
+
+            info!("🔐 requesting ACME certificate for {}", self.domain);
+            match self.issue().await {
+                Ok(()) => info!("🔐 ACME certificate issued for {}", self.domain),
+                Err(err) => {
+                    error!("failed to issue ACME certificate: {err}");
+                    time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn issue(&self) -> Result<()> {
+        let account = self.account().await?;
+        let identifiers = identifiers(&self.domain, self.wildcard);
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let mut dns_records = Vec::new();
+        let result = self.authorize(&mut order, &mut dns_records).await;
+        let status = match result {
+            Ok(()) => order.poll_ready(&RetryPolicy::default()).await,
+            Err(err) => Err(err),
+        };
+        self.clear_challenges(dns_records).await;
+
+        let status = status?;
+        if status != OrderStatus::Ready {
+            return Err(format!("unexpected ACME order status: {status:?}").into());
+        }
+
+        let key_pem = order.finalize().await?;
+        let cert_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+
+        fs::write(self.cache_dir.join("cert.pem"), &cert_pem).await?;
+        fs::write(self.cache_dir.join("key.pem"), &key_pem).await?;
+
+        self.resolver
+            .set_certificate(certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?);
+
+        Ok(())
+    }
+
+    async fn authorize(
+        &self,
+        order: &mut Order,
+        dns_records: &mut Vec<(String, String)>,
+    ) -> std::result::Result<(), instant_acme::Error> {
+        let mut authorizations = order.authorizations();
+        while let Some(result) = authorizations.next().await {
+            let mut authz = result?;
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => {
+                    return Err(instant_acme::Error::Other(
+                        format!("unexpected ACME authorization status: {status:?}").into(),
+                    ));
+                }
+            }
+
+            let mut challenge = authz.challenge(self.challenge.into()).ok_or_else(|| {
+                instant_acme::Error::Other(
+                    format!("ACME server did not offer {:?} challenge", self.challenge).into(),
+                )
+            })?;
+
+            let Identifier::Dns(domain) = challenge.identifier().identifier.clone() else {
+                return Err(instant_acme::Error::Str(
+                    "only DNS identifiers are supported",
+                ));
+            };
+            let key_authorization = challenge.key_authorization();
+
+            match self.challenge {
+                Challenge::Http01 => {
+                    if let Ok(mut tokens) = self.http_tokens.write() {
+                        tokens.insert(
+                            challenge.token.clone(),
+                            key_authorization.as_str().to_string(),
+                        );
+                    }
+                }
+                Challenge::TlsAlpn01 => {
+                    let certificate = challenge_certificate(&domain, key_authorization.digest())
+                        .map_err(instant_acme::Error::Other)?;
+                    self.resolver.add_challenge(&domain, certificate);
+                }
+                Challenge::Dns01 => {
+                    let record = format!("_acme-challenge.{domain}");
+                    let value = key_authorization.dns_value();
+                    self.run_dns_hook("present", &record, &value)
+                        .await
+                        .map_err(instant_acme::Error::Other)?;
+                    dns_records.push((record, value));
+                }
+            }
+
+            challenge.set_ready().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn clear_challenges(&self, dns_records: Vec<(String, String)>) {
+        if let Ok(mut tokens) = self.http_tokens.write() {
+            tokens.clear();
+        }
+        self.resolver.clear_challenges();
+
+        for (record, value) in dns_records {
+            if let Err(err) = self.run_dns_hook("cleanup", &record, &value).await {
+                warn!("failed to clean up DNS record {record}: {err}");
+            }
+        }
+    }
+
+    async fn run_dns_hook(&self, action: &str, record: &str, value: &str) -> Result<()> {
+        let hook = self
+            .dns_hook
+            .as_deref()
+            .ok_or("RUSTY_RELAY_ACME_DNS_HOOK is not set")?;
+
+        let status = Command::new(hook)
+            .arg(action)
+            .arg(record)
+            .arg(value)
+            .status()
+            .await?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("DNS hook '{hook} {action}' exited with {status}").into())
+        }
+    }
+
+    async fn account(&self) -> Result<Account> {
+        let builder = match self.ca_cert.as_ref() {
+            Some(ca_cert) => Account::builder_with_root(ca_cert)?,
+            None => Account::builder()?,
+        };
+
+        let path = self.cache_dir.join("account.json");
+        if let Ok(json) = fs::read(&path).await {
+            let credentials = serde_json::from_slice::<AccountCredentials>(&json)?;
+            return Ok(builder.from_credentials(credentials).await?);
+        }
+
+        let contact = self.contact.as_ref().map(|email| format!("mailto:{email}"));
+        let contact = contact
+            .as_slice()
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let (account, credentials) = builder
+            .create(
+                &NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.directory.clone(),
+                None,
+            )
+            .await?;
+
+        fs::write(&path, serde_json::to_vec(&credentials)?).await?;
+
+        Ok(account)
+    }
+
+    async fn load_cached_certificate(&self) -> Option<CertifiedKey> {
+        let cert_pem = fs::read(self.cache_dir.join("cert.pem")).await.ok()?;
+        let key_pem = fs::read(self.cache_dir.join("key.pem")).await.ok()?;
+
+        certified_key(&cert_pem, &key_pem)
+            .map_err(|err| warn!("ignoring cached ACME certificate: {err}"))
+            .ok()
+    }
+
+    async fn serve_http_challenges(self: Arc<Self>) {
+        let addr = SocketAddr::from((
+            [0, 0, 0, 0],
+            from_env_or_else("RUSTY_RELAY_HTTP_PORT", || 8080),
+        ));
+        let router = Router::new()
+            .route(
+                "/.well-known/acme-challenge/{token}",
+                routing::get(http_challenge_handler),
+            )
+            .with_state(self);
+
+        info!("🔐 serving ACME http-01 challenges on http://{addr}");
+
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                if let Err(err) = axum::serve(listener, router).await {
+                    error!("ACME http-01 challenge server stopped: {err}");
+                }
+            }
+            Err(err) => error!("failed to bind ACME http-01 challenge server: {err}"),
+        }
+    }
+}
+
+async fn http_challenge_handler(
+    State(acme): State<Arc<Acme>>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    let key_authorization = acme
+        .http_tokens
+        .read()
+        .ok()
+        .and_then(|tokens| tokens.get(&token).cloned());
+
+    match key_authorization {
+        Some(key_authorization) => key_authorization.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Serves the issued certificate, or the TLS-ALPN-01 challenge certificate
+/// when the ACME server connects with the `acme-tls/1` protocol.
+#[derive(Debug, Default)]
+struct CertResolver {
+    certificate: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    fn set_certificate(&self, certificate: CertifiedKey) {
+        if let Ok(mut current) = self.certificate.write() {
+            *current = Some(Arc::new(certificate));
+        }
+    }
+
+    fn add_challenge(&self, domain: &str, certificate: CertifiedKey) {
+        if let Ok(mut challenges) = self.challenges.write() {
+            challenges.insert(domain.to_string(), Arc::new(certificate));
+        }
+    }
+
+    fn clear_challenges(&self) {
+        if let Ok(mut challenges) = self.challenges.write() {
+            challenges.clear();
+        }
+    }
+
+    fn renew_at(&self) -> Option<SystemTime> {
+        let current = self.certificate.read().ok()?;
+        let certificate = current.as_ref()?.end_entity_cert().ok()?;
+        renew_at(certificate)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+
+        if is_challenge {
+            let server_name = client_hello.server_name()?;
+            self.challenges.read().ok()?.get(server_name).cloned()
+        } else {
+            self.certificate.read().ok()?.clone()
+        }
+    }
+}
+
+fn identifiers(domain: &str, wildcard: bool) -> Vec<Identifier> {
+    let mut identifiers = vec![Identifier::Dns(domain.to_string())];
+    if wildcard {
+        identifiers.push(Identifier::Dns(format!("*.{domain}")));
+    }
+    identifiers
+}
+
+fn renew_at(certificate: &CertificateDer<'_>) -> Option<SystemTime> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let not_after = u64::try_from(certificate.validity().not_after.timestamp()).ok()?;
+
+    (SystemTime::UNIX_EPOCH + Duration::from_secs(not_after)).checked_sub(RENEW_BEFORE)
+}
+
+fn crypto_provider() -> Result<&'static Arc<CryptoProvider>> {
+    CryptoProvider::get_default().ok_or_else(|| "no default crypto provider installed".into())
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem).collect::<std::result::Result<_, _>>()?;
+    let key = PrivateKeyDer::from_pem_slice(key_pem)?;
+
+    Ok(CertifiedKey::from_der(certs, key, crypto_provider()?)?)
+}
+
+fn challenge_certificate(domain: &str, digest: impl AsRef<[u8]>) -> Result<CertifiedKey> {
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let certificate = params.self_signed(&key_pair)?;
+    let key = PrivateKeyDer::try_from(key_pair.serialize_der())?;
+
+    Ok(CertifiedKey::from_der(
+        vec![certificate.der().clone()],
+        key,
+        crypto_provider()?,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::generate_id;
+
+    #[test]
+    fn test_identifiers() {
+        let domains = identifiers("relay.example.com", true)
+            .into_iter()
+            .map(|identifier| match identifier {
+                Identifier::Dns(domain) => domain,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(domains, vec!["relay.example.com", "*.relay.example.com"]);
+    }
+
+    #[test]
+    fn test_renew_at() {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 3, 31);
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key_pair).unwrap();
+
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(1_901_145_600) - RENEW_BEFORE;
+        assert_eq!(renew_at(certificate.der()), Some(expected));
+    }
+
+    /// Runs against the Pebble directory in `RUSTY_RELAY_TEST_PEBBLE_DIRECTORY` (e.g.
+    /// `https://localhost:14000/dir`) with its CA in `RUSTY_RELAY_TEST_PEBBLE_CA_CERT`, and passes
+    /// without one. Pebble must run with `PEBBLE_VA_ALWAYS_VALID=1`, since nothing serves the
+    /// challenges to it.
+    #[tokio::test]
+    async fn test_issue_and_renew_with_pebble() {
+        let (Ok(directory), Ok(ca_cert)) = (
+            std::env::var("RUSTY_RELAY_TEST_PEBBLE_DIRECTORY"),
+            std::env::var("RUSTY_RELAY_TEST_PEBBLE_CA_CERT"),
+        ) else {
+            return;
+        };
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let cache_dir = std::env::temp_dir().join(format!("rusty-relay-acme-{}", generate_id(8)));
+        let acme = |cache_dir: PathBuf| Acme {
+            domain: "relay.example.com".to_string(),
+            wildcard: false,
+            contact: None,
+            directory: directory.clone(),
+            ca_cert: Some(ca_cert.clone()),
+            challenge: Challenge::Http01,
+            dns_hook: None,
+            cache_dir,
+            resolver: Arc::new(CertResolver::default()),
+            http_tokens: RwLock::new(HashMap::new()),
+        };
+        let certificate = |acme: &Acme| -> CertificateDer<'static> {
+            let current = acme.resolver.certificate.read().unwrap();
+            let certificate = current.as_ref().unwrap().end_entity_cert().unwrap();
+            certificate.clone().into_owned()
+        };
+
+        let first = acme(cache_dir.clone());
+        fs::create_dir_all(&cache_dir).await.unwrap();
+        first.issue().await.unwrap();
+        let issued = certificate(&first);
+        assert!(first.resolver.renew_at().unwrap() > SystemTime::now());
+
+        // a restarted server renews with the cached account and replaces the cached certificate
+        let second = acme(cache_dir.clone());
+        let cached = second.load_cached_certificate().await.unwrap();
+        assert_eq!(cached.end_entity_cert().unwrap(), &issued);
+        second.issue().await.unwrap();
+        let renewed = certificate(&second);
+        assert_ne!(renewed, issued);
+
+        let cached = acme(cache_dir.clone()).load_cached_certificate().await;
+        assert_eq!(cached.unwrap().end_entity_cert().unwrap(), &renewed);
+
+        fs::remove_dir_all(&cache_dir).await.unwrap();
+    }
+}
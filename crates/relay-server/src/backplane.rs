@@ -0,0 +1,518 @@
+use crate::{
+    error::{BoxError, HttpError},
+    proxy,
+    state::AppState,
+    util::{self, from_env_or_else, generate_id},
+    webhook,
+};
+use axum::response::IntoResponse;
+use rusty_relay_messages::{ClientOptions, RelayMessage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{debug, error, info, warn};
+
+mod redis;
+
+pub use self::redis::RedisBackplane;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// How often the clients connected to this instance are registered again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A message sent from one server instance to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Envelope {
+    /// A webhook or proxy request for a client connected to the receiving instance
+    Deliver {
+        reply_to: String,
+        client_id: String,
+        message: RelayMessage,
+        /// Set when the sender awaits the outcome of a webhook, it is responded as a
+        /// `ProxyResponse` with this id and the status the webhook got
+        #[serde(default)]
+        webhook_id: Option<String>,
+    },
+    /// The response to a proxy request that was delivered by `instance_id`
+    Respond {
+        instance_id: String,
+        message: RelayMessage,
+    },
+    /// The caller of a delivered proxy request went away, so the client should stop working on it
+    Cancel { request_id: String },
+}
+
+/// Another instance a client is connected to.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub instance_id: String,
+    pub options: ClientOptions,
+}
+
+/// Connects server instances, so a request hitting one instance can reach a client
+/// connected to another.
+pub trait Backplane: Send + Sync {
+    fn instance_id(&self) -> &str;
+
+    /// Announces that `client_id` is connected to this instance.
+    fn register<'a>(
+        &'a self,
+        client_id: &'a str,
+        options: &'a ClientOptions,
+    ) -> BoxFuture<'a, Result<(), BoxError>>;
+
+    fn unregister<'a>(&'a self, client_id: &'a str) -> BoxFuture<'a, Result<(), BoxError>>;
+
+    /// Finds another instance `client_id` is connected to.
+    fn locate<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Location>, BoxError>>;
+
+    fn publish<'a>(
+        &'a self,
+        instance_id: &'a str,
+        envelope: Envelope,
+    ) -> BoxFuture<'a, Result<(), BoxError>>;
+
+    /// Receives the envelopes published to this instance.
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<Envelope>, BoxError>>;
+
+    /// Whether envelopes published to this instance are currently received.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+/// Creates the backplane from `RUSTY_RELAY_BACKPLANE_URL`, without it the server runs standalone.
+pub async fn from_env() -> Result<Arc<dyn Backplane>, BoxError> {
+    let instance_id = from_env_or_else("RUSTY_RELAY_INSTANCE_ID", || generate_id(12));
+
+    match std::env::var("RUSTY_RELAY_BACKPLANE_URL") {
+        Ok(url) => {
+            let backplane = RedisBackplane::connect(&url, instance_id).await?;
+            info!(
+                instance_id = backplane.instance_id(),
+                "🛰 connected to redis backplane"
+            );
+            Ok(Arc::new(backplane))
+        }
+        Err(_) => Ok(Arc::new(InMemoryBackplane::new(instance_id))),
+    }
+}
+
+/// Proxy requests from other instances that are forwarded to a local client, by request id.
+type Forwarding = Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>;
+
+/// Handles the envelopes from other instances and keeps the local clients registered.
+pub async fn start(state: Arc<AppState>) -> Result<(), BoxError> {
+    let mut envelopes = state.backplane().subscribe().await?;
+    let forwarding = Forwarding::default();
+
+    tokio::spawn(refresh(state.clone()));
+    tokio::spawn(async move {
+        while let Some(envelope) = envelopes.recv().await {
+            match envelope {
+                Envelope::Cancel { request_id } => {
+                    let cancel = forwarding
+                        .lock()
+                        .expect("lock should not be poisoned")
+                        .remove(&request_id);
+                    if let Some(cancel) = cancel {
+                        let _ = cancel.send(());
+                    }
+                }
+                Envelope::Deliver {
+                    message: RelayMessage::ProxyRequest { ref request_id, .. },
+                    ..
+                } => {
+                    let request_id = request_id.clone();
+                    let (cancel, cancelled) = oneshot::channel();
+                    forwarding
+                        .lock()
+                        .expect("lock should not be poisoned")
+                        .insert(request_id.clone(), cancel);
+
+                    let state = state.clone();
+                    let forwarding = forwarding.clone();
+                    tokio::spawn(async move {
+                        // dropping the forward tells the client to cancel the request
+                        tokio::select! {
+                            _ = handle(state, envelope) => {}
+                            _ = cancelled => debug!(request_id, "🚫 proxy request cancelled by other instance"),
+                        }
+                        forwarding
+                            .lock()
+                            .expect("lock should not be poisoned")
+                            .remove(&request_id);
+                    });
+                }
+                envelope => {
+                    tokio::spawn(handle(state.clone(), envelope));
+                }
+            }
+        }
+        error!("❌ backplane subscription ended, requests from other instances are lost");
+    });
+
+    Ok(())
+}
+
+async fn refresh(state: Arc<AppState>) {
+    let mut interval = time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (client_id, options) in state.local_clients().await {
+            if let Err(err) = state.backplane().register(&client_id, &options).await {
+                warn!(client_id, "failed to register client on backplane: {err}");
+            }
+        }
+    }
+}
+
+async fn handle(state: Arc<AppState>, envelope: Envelope) {
+    match envelope {
+        Envelope::Deliver {
+            reply_to,
+            client_id,
+            message,
+            webhook_id,
+        } if matches!(message, RelayMessage::Webhook { .. }) => {
+            let result = webhook::deliver(&state, &client_id, message).await;
+            if let Err(err) = &result {
+                warn!(
+                    client_id,
+                    ?err,
+                    "❌ webhook from backplane was not delivered"
+                );
+            }
+
+            if let Some(request_id) = webhook_id {
+                let response = match result {
+                    Ok(()) => RelayMessage::ProxyResponse {
+                        request_id,
+                        headers: Vec::new(),
+                        body: Vec::new(),
+                        status: 200,
+                    },
+                    Err(err) => error_response(request_id, err).await,
+                };
+                respond(&state, &reply_to, response).await;
+            }
+        }
+        Envelope::Deliver {
+            reply_to,
+            client_id,
+            message,
+            ..
+        } => {
+            let RelayMessage::ProxyRequest {
+                request_id, path, ..
+            } = &message
+            else {
+                return;
+            };
+            let request_id = request_id.clone();
+            let timeout = state
+                .client_config(&client_id)
+                .await
+                .ok()
+                .and_then(|config| config.proxy_timeout(path.as_deref()))
+                .unwrap_or(state.proxy_timeout());
+
+            let response =
+                match proxy::forward_local(&state, &client_id, &request_id, message, timeout).await
+                {
+                    Ok(response) => response,
+                    Err(err) => error_response(request_id, err).await,
+                };
+            respond(&state, &reply_to, response).await;
+        }
+        Envelope::Respond {
+            instance_id,
+            message,
+        } => {
+            if let RelayMessage::ProxyResponse { request_id, .. }
+            | RelayMessage::ProxyError { request_id, .. } = &message
+            {
+                let connection_id = remote_connection_id(&instance_id);
+                if let Some(tx) = state.take_proxy_request(request_id, &connection_id).await {
+                    let _ = tx.send(message);
+                }
+            }
+        }
+        // handled by `start`
+        Envelope::Cancel { .. } => {}
+    }
+}
+
+/// The error as it would be returned to the caller, to return it on the other instance.
+async fn error_response(request_id: String, err: HttpError) -> RelayMessage {
+    let (parts, body) = err.into_response().into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .unwrap_or_default();
+
+    RelayMessage::ProxyResponse {
+        request_id,
+        headers: util::into_headers(parts.headers),
+        body: body.to_vec(),
+        status: parts.status.as_u16(),
+    }
+}
+
+async fn respond(state: &AppState, reply_to: &str, message: RelayMessage) {
+    let envelope = Envelope::Respond {
+        instance_id: state.backplane().instance_id().to_string(),
+        message,
+    };
+    if let Err(err) = state.backplane().publish(reply_to, envelope).await {
+        warn!(
+            reply_to,
+            "failed to publish proxy response on backplane: {err}"
+        );
+    }
+}
+
+/// The connection id under which proxy requests sent to another instance are tracked.
+pub fn remote_connection_id(instance_id: &str) -> String {
+    format!("instance:{instance_id}")
+}
+
+#[derive(Default)]
+struct Hub {
+    clients: Mutex<HashMap<String, (ClientOptions, Vec<String>)>>,
+    instances: Mutex<HashMap<String, mpsc::Sender<Envelope>>>,
+}
+
+/// Backplane within a single process, used when running a single instance.
+pub struct InMemoryBackplane {
+    instance_id: String,
+    hub: Arc<Hub>,
+}
+
+impl InMemoryBackplane {
+    pub fn new(instance_id: String) -> Self {
+        Self {
+            instance_id,
+            hub: Arc::default(),
+        }
+    }
+
+    /// Another instance on the same backplane.
+    #[cfg(test)]
+    pub fn join(&self, instance_id: String) -> Self {
+        Self {
+            instance_id,
+            hub: self.hub.clone(),
+        }
+    }
+}
+
+impl Backplane for InMemoryBackplane {
+    fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    fn register<'a>(
+        &'a self,
+        client_id: &'a str,
+        options: &'a ClientOptions,
+    ) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            let mut clients = self
+                .hub
+                .clients
+                .lock()
+                .expect("lock should not be poisoned");
+            let (registered, instances) = clients
+                .entry(client_id.to_string())
+                .or_insert_with(|| (options.clone(), Vec::new()));
+            *registered = options.clone();
+            if !instances.contains(&self.instance_id) {
+                instances.push(self.instance_id.clone());
+            }
+            Ok(())
+        })
+    }
+
+    fn unregister<'a>(&'a self, client_id: &'a str) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            let mut clients = self
+                .hub
+                .clients
+                .lock()
+                .expect("lock should not be poisoned");
+            if let Some((_, instances)) = clients.get_mut(client_id) {
+                instances.retain(|instance_id| *instance_id != self.instance_id);
+                if instances.is_empty() {
+                    clients.remove(client_id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn locate<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Location>, BoxError>> {
+        Box::pin(async move {
+            let clients = self
+                .hub
+                .clients
+                .lock()
+                .expect("lock should not be poisoned");
+            Ok(clients.get(client_id).and_then(|(options, instances)| {
+                instances
+                    .iter()
+                    .find(|instance_id| **instance_id != self.instance_id)
+                    .map(|instance_id| Location {
+                        instance_id: instance_id.clone(),
+                        options: options.clone(),
+                    })
+            }))
+        })
+    }
+
+    fn publish<'a>(
+        &'a self,
+        instance_id: &'a str,
+        envelope: Envelope,
+    ) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            let sender = self
+                .hub
+                .instances
+                .lock()
+                .expect("lock should not be poisoned")
+                .get(instance_id)
+                .cloned()
+                .ok_or_else(|| format!("unknown instance: {instance_id}"))?;
+
+            sender.send(envelope).await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<Envelope>, BoxError>> {
+        Box::pin(async move {
+            let (sender, receiver) = mpsc::channel(100);
+            self.hub
+                .instances
+                .lock()
+                .expect("lock should not be poisoned")
+                .insert(self.instance_id.clone(), sender);
+            Ok(receiver)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::HttpError, state::ClientConfig};
+
+    #[tokio::test]
+    async fn test_join_across_instances() {
+        let backplane_a = InMemoryBackplane::new("a".to_string());
+        let backplane_b = backplane_a.join("b".to_string());
+
+        let state_a = AppState::with_backplane(Arc::new(backplane_a));
+        let state_b = AppState::with_backplane(Arc::new(backplane_b));
+
+        let named = || {
+            ClientConfig::try_from(ClientOptions {
+                name: Some("shop".to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let _alice = state_b
+            .add_client("alice", false, ClientConfig::default())
+            .await
+            .unwrap();
+        let _shop = state_b.add_client("shop", true, named()).await.unwrap();
+
+        assert!(matches!(
+            state_a
+                .can_join("alice", false, &ClientConfig::default())
+                .await,
+            Err(HttpError::Conflict(_))
+        ));
+        assert!(state_a.can_join("shop", true, &named()).await.is_ok());
+
+        let other_options = ClientConfig::try_from(ClientOptions {
+            name: Some("shop".to_string()),
+            proxy_timeout: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            state_a.can_join("shop", true, &other_options).await,
+            Err(HttpError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_across_instances() {
+        let backplane_a = InMemoryBackplane::new("a".to_string());
+        let backplane_b = backplane_a.join("b".to_string());
+
+        let state_a = Arc::new(AppState::with_backplane(Arc::new(backplane_a)));
+        let state_b = Arc::new(AppState::with_backplane(Arc::new(backplane_b)));
+        start(state_a.clone()).await.unwrap();
+        start(state_b.clone()).await.unwrap();
+
+        // the client is connected to instance b and answers every proxy request
+        let (client, mut rx) = state_b
+            .add_client("shop", true, ClientConfig::default())
+            .await
+            .unwrap();
+        let responder = state_b.clone();
+        tokio::spawn(async move {
+            while let Some(RelayMessage::ProxyRequest { request_id, .. }) = rx.recv().await {
+                let tx = responder
+                    .take_proxy_request(&request_id, &client.connection_id)
+                    .await
+                    .unwrap();
+                let _ = tx.send(RelayMessage::ProxyResponse {
+                    request_id,
+                    headers: Vec::new(),
+                    body: b"from b".to_vec(),
+                    status: 200,
+                });
+            }
+        });
+
+        assert!(state_a.client_config("shop").await.is_ok());
+
+        let request = RelayMessage::ProxyRequest {
+            request_id: "request-1".to_string(),
+            method: "GET".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            path: None,
+            query: None,
+        };
+        let timeout = state_a.proxy_timeout();
+        let response = proxy::forward(&state_a, "shop", "request-1", request, timeout)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            response,
+            RelayMessage::ProxyResponse { status: 200, body, .. } if body == b"from b"
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+use super::{Backplane, BoxFuture, Envelope, Location, REFRESH_INTERVAL};
+use crate::error::BoxError;
+use ::redis::{
+    AsyncCommands, Client, RedisResult,
+    aio::{ConnectionManager, PubSub},
+};
+use rusty_relay_messages::ClientOptions;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, time};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+const KEY_PREFIX: &str = "rusty-relay";
+
+/// Longest wait between attempts to subscribe again after losing the connection.
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Backplane using a redis hash per client id (options and the instances it is connected to,
+/// each with an expiry) and a pub/sub channel per instance.
+pub struct RedisBackplane {
+    instance_id: String,
+    client: Client,
+    connection: ConnectionManager,
+    subscribed: Arc<AtomicBool>,
+}
+
+impl RedisBackplane {
+    pub async fn connect(url: &str, instance_id: String) -> Result<Self, BoxError> {
+        let client = Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+
+        Ok(Self {
+            instance_id,
+            client,
+            connection,
+            subscribed: Arc::default(),
+        })
+    }
+}
+
+fn client_key(client_id: &str) -> String {
+    format!("{KEY_PREFIX}:client:{client_id}")
+}
+
+fn instance_field(instance_id: &str) -> String {
+    format!("instance:{instance_id}")
+}
+
+fn instance_channel(instance_id: &str) -> String {
+    format!("{KEY_PREFIX}:instance:{instance_id}")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Backplane for RedisBackplane {
+    fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    fn register<'a>(
+        &'a self,
+        client_id: &'a str,
+        options: &'a ClientOptions,
+    ) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            // missing three refreshes in a row means the instance is gone
+            let ttl = REFRESH_INTERVAL.as_secs() * 3;
+            let key = client_key(client_id);
+            let fields = [
+                ("options".to_string(), serde_json::to_string(options)?),
+                (instance_field(&self.instance_id), (now() + ttl).to_string()),
+            ];
+
+            let mut connection = self.connection.clone();
+            ::redis::pipe()
+                .hset_multiple(&key, &fields)
+                .expire(&key, ttl as i64)
+                .exec_async(&mut connection)
+                .await?;
+
+            Ok(())
+        })
+    }
+
+    fn unregister<'a>(&'a self, client_id: &'a str) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let _: () = connection
+                .hdel(client_key(client_id), instance_field(&self.instance_id))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn locate<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Location>, BoxError>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let fields: HashMap<String, String> = connection.hgetall(client_key(client_id)).await?;
+
+            let now = now();
+            let instance_id = fields.iter().find_map(|(field, expires_at)| {
+                let instance_id = field.strip_prefix("instance:")?;
+                let is_alive = expires_at.parse::<u64>().is_ok_and(|at| at > now);
+                (is_alive && instance_id != self.instance_id).then(|| instance_id.to_string())
+            });
+
+            match (instance_id, fields.get("options")) {
+                (Some(instance_id), Some(options)) => Ok(Some(Location {
+                    instance_id,
+                    options: serde_json::from_str(options)?,
+                })),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn publish<'a>(
+        &'a self,
+        instance_id: &'a str,
+        envelope: Envelope,
+    ) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let receivers: usize = connection
+                .publish(
+                    instance_channel(instance_id),
+                    serde_json::to_vec(&envelope)?,
+                )
+                .await?;
+
+            if receivers == 0 {
+                return Err(format!("instance is not subscribed: {instance_id}").into());
+            }
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<Envelope>, BoxError>> {
+        Box::pin(async move {
+            let channel = instance_channel(&self.instance_id);
+            let pubsub = subscribe_to(&self.client, &channel).await?;
+            self.subscribed.store(true, Ordering::Relaxed);
+
+            let (sender, receiver) = mpsc::channel(100);
+            let client = self.client.clone();
+            let subscribed = self.subscribed.clone();
+            tokio::spawn(async move {
+                let mut pubsub = pubsub;
+                while forward(pubsub, &sender).await {
+                    subscribed.store(false, Ordering::Relaxed);
+                    warn!("❌ lost redis backplane subscription, subscribing again");
+                    pubsub = resubscribe(&client, &channel).await;
+                    subscribed.store(true, Ordering::Relaxed);
+                    info!("🛰 redis backplane subscription restored");
+                }
+            });
+
+            Ok(receiver)
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+}
+
+async fn subscribe_to(client: &Client, channel: &str) -> RedisResult<PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    Ok(pubsub)
+}
+
+/// Subscribes again until it succeeds, waiting longer after every failed attempt.
+async fn resubscribe(client: &Client, channel: &str) -> PubSub {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        time::sleep(backoff).await;
+        match subscribe_to(client, channel).await {
+            Ok(pubsub) => return pubsub,
+            Err(err) => {
+                warn!("failed to subscribe to redis backplane: {err}");
+                backoff = (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Passes the envelopes on until the connection drops (true) or nobody receives them (false).
+async fn forward(pubsub: PubSub, sender: &mpsc::Sender<Envelope>) -> bool {
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        match serde_json::from_slice::<Envelope>(message.get_payload_bytes()) {
+            Ok(envelope) => {
+                if sender.send(envelope).await.is_err() {
+                    return false;
+                }
+            }
+            Err(err) => warn!("failed to deserialize envelope from redis: {err}"),
+        }
+    }
+    true
+}
+
+/// These run against the redis server in `RUSTY_RELAY_TEST_REDIS_URL` and pass without one.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::generate_id;
+    use rusty_relay_messages::RelayMessage;
+
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn connect_pair() -> Option<(RedisBackplane, RedisBackplane)> {
+        let url = std::env::var("RUSTY_RELAY_TEST_REDIS_URL").ok()?;
+        let connect = |name| {
+            let instance_id = format!("{name}-{}", generate_id(8));
+            RedisBackplane::connect(&url, instance_id)
+        };
+        Some((connect("a").await.unwrap(), connect("b").await.unwrap()))
+    }
+
+    async fn receive(receiver: &mut mpsc::Receiver<Envelope>) -> Envelope {
+        time::timeout(RECEIVE_TIMEOUT, receiver.recv())
+            .await
+            .expect("envelope should arrive")
+            .expect("subscription should be open")
+    }
+
+    #[tokio::test]
+    async fn test_register_and_locate() {
+        let Some((backplane_a, backplane_b)) = connect_pair().await else {
+            return;
+        };
+        let client_id = format!("shop-{}", generate_id(8));
+        let options = ClientOptions {
+            name: Some("shop".to_string()),
+            ..Default::default()
+        };
+
+        assert!(backplane_b.locate(&client_id).await.unwrap().is_none());
+        backplane_a.register(&client_id, &options).await.unwrap();
+
+        let location = backplane_b.locate(&client_id).await.unwrap().unwrap();
+        assert_eq!(location.instance_id, backplane_a.instance_id());
+        assert_eq!(location.options.name.as_deref(), Some("shop"));
+        // an instance never locates its own clients
+        assert!(backplane_a.locate(&client_id).await.unwrap().is_none());
+
+        backplane_a.unregister(&client_id).await.unwrap();
+        assert!(backplane_b.locate(&client_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_respond_cancel_and_resubscribe() {
+        let Some((backplane_a, backplane_b)) = connect_pair().await else {
+            return;
+        };
+        let (instance_a, instance_b) = (backplane_a.instance_id(), backplane_b.instance_id());
+
+        // nobody listens on an instance that didn't subscribe
+        let cancel = || Envelope::Cancel {
+            request_id: "request-1".to_string(),
+        };
+        assert!(backplane_a.publish(instance_b, cancel()).await.is_err());
+        assert!(!backplane_b.is_healthy());
+
+        let mut receiver_a = backplane_a.subscribe().await.unwrap();
+        let mut receiver_b = backplane_b.subscribe().await.unwrap();
+        assert!(backplane_b.is_healthy());
+
+        let deliver = Envelope::Deliver {
+            reply_to: instance_a.to_string(),
+            client_id: "shop".to_string(),
+            message: RelayMessage::Webhook {
+                method: "POST".to_string(),
+                headers: Vec::new(),
+                body: b"order".to_vec(),
+            },
+            webhook_id: Some("webhook-1".to_string()),
+        };
+        backplane_a.publish(instance_b, deliver).await.unwrap();
+        assert!(matches!(
+            receive(&mut receiver_b).await,
+            Envelope::Deliver { reply_to, webhook_id: Some(_), .. } if reply_to == instance_a
+        ));
+
+        let respond = Envelope::Respond {
+            instance_id: instance_b.to_string(),
+            message: RelayMessage::ProxyResponse {
+                request_id: "webhook-1".to_string(),
+                status: 200,
+                headers: Vec::new(),
+                body: Vec::new(),
+            },
+        };
+        backplane_b.publish(instance_a, respond).await.unwrap();
+        assert!(matches!(
+            receive(&mut receiver_a).await,
+            Envelope::Respond { instance_id, .. } if instance_id == instance_b
+        ));
+
+        backplane_a.publish(instance_b, cancel()).await.unwrap();
+        assert!(matches!(
+            receive(&mut receiver_b).await,
+            Envelope::Cancel { request_id } if request_id == "request-1"
+        ));
+
+        // killing the connections drops every subscription on the server, so it's done here
+        // rather than in a test of its own running alongside this one
+        let mut connection = backplane_a.connection.clone();
+        let _: usize = ::redis::cmd("CLIENT")
+            .arg(&["KILL", "TYPE", "pubsub"])
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+
+        time::timeout(RECEIVE_TIMEOUT, async {
+            while backplane_a.publish(instance_b, cancel()).await.is_err() {
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("subscription should be restored");
+        assert!(matches!(
+            receive(&mut receiver_b).await,
+            Envelope::Cancel { .. }
+        ));
+        assert!(backplane_b.is_healthy());
+    }
+}
@@ -1,69 +1,97 @@
 use crate::{
-    error::HttpError,
+    error::{ErrorFormat, HttpError},
+    forwarded::{self, CLIENT_ID_COOKIE, Forwarded},
+    proxy,
     state::AppState,
+    tls::ClientIdentity,
     util::{self, generate_id},
 };
 use axum::{
+    Extension,
     body::{Body, Bytes},
-    extract::{Path, Query, State},
-    http::{HeaderMap, Method, StatusCode},
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
     response::{IntoResponse, Redirect},
 };
 use axum_extra::extract::CookieJar;
 use rusty_relay_messages::RelayMessage;
-use std::{ops::Not, sync::Arc};
-use tokio::sync::oneshot;
-use tracing::{debug, info};
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{debug, info, warn};
 
-#[tracing::instrument(skip(state, jar, body))]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(state, headers, jar, body))]
 pub async fn catch_all_handler(
     state: State<Arc<AppState>>,
-    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    mut headers: HeaderMap,
     method: Method,
-    Path(path): Path<String>,
-    Query(params): Query<Vec<(String, String)>>,
+    uri: Uri,
     jar: CookieJar,
     body: Bytes,
 ) -> impl IntoResponse {
     let request_id = generate_id(20);
     info!(request_id, "🖥 proxy request received");
 
-    if let Some(client_id) = jar.get("client_id") {
-        if let Some(sender) = state.get_client(client_id.value()).await {
-            let query = params.is_empty().not().then(|| util::get_query(params));
-            let _ = sender.send(RelayMessage::ProxyRequest {
-                request_id: request_id.clone(),
-                path: Some(path),
-                query,
-                method: method.to_string(),
-                headers: util::into_hashmap(headers),
-                body: body.to_vec(),
-            });
+    if let Some(client_id) = jar.get(CLIENT_ID_COOKIE) {
+        let forwarded = Forwarded::new(addr, &headers, state.trusted_proxies(), identity.is_some());
+        let caller_ip = forwarded.caller_ip;
+        // the protection of the client is unknown, so the request can't be let through
+        let config = match state.client_config(client_id.value()).await {
+            Ok(config) => config,
+            Err(err) => return err.into_response(),
+        };
+        if let Err(err) = state.check_rate_limit(client_id.value(), caller_ip).await {
+            warn!(request_id, %caller_ip, "❌ proxy request rate limited");
+            return err.into_response();
+        }
+        if let Err(err) = config.access.check(&mut headers, caller_ip) {
+            debug!(request_id, %caller_ip, "❌ proxy request denied");
+            return err.into_response();
         }
 
-        let (resp_tx, resp_rx) = oneshot::channel();
-
-        state.add_proxy_request(&request_id, resp_tx).await;
+        let path = util::raw_path(&uri, 0).to_string();
+        let format = ErrorFormat::from(&headers);
+        forwarded.apply(&mut headers);
+        let timeout = config
+            .proxy_timeout(Some(&path))
+            .unwrap_or(state.proxy_timeout());
+        let request = RelayMessage::ProxyRequest {
+            request_id: request_id.clone(),
+            path: Some(path),
+            query: uri.query().map(str::to_string),
+            method: method.to_string(),
+            headers: util::into_headers(headers),
+            body: body.to_vec(),
+        };
 
-        match tokio::time::timeout(state.proxy_timeout(), resp_rx).await {
-            Ok(Ok(RelayMessage::ProxyResponse {
+        match proxy::forward(&state, client_id.value(), &request_id, request, timeout).await {
+            Ok(RelayMessage::ProxyResponse {
                 body,
-                headers,
+                mut headers,
                 status,
                 ..
-            })) => {
+            }) => {
+                forwarded::retain_end_to_end(&mut headers);
                 let mut response = axum::response::Response::builder().status(status);
 
                 for (k, v) in headers.iter().filter(|(k, _)| *k != "content-length") {
-                    response = response.header(k, v);
+                    response = response.header(k, v.as_slice());
                 }
 
                 response
                     .body(Body::from(body))
-                    .map_err(|e| HttpError::BadRequest(e.to_string()))
+                    .map_err(|e| HttpError::BadGateway(e.to_string()))
                     .into_response()
             }
-            _ => HttpError::GatewayTimeout("Timeout".to_string()).into_response(),
+            Ok(RelayMessage::ProxyError { kind, message, .. }) => HttpError::Target {
+                kind,
+                message,
+                format,
+            }
+            .into_response(),
+            Ok(_) => HttpError::BadGateway("Unexpected response".to_string()).into_response(),
+            Err(err) => err.into_response(),
         }
     } else {
         StatusCode::OK.into_response()
@@ -71,16 +99,15 @@ pub async fn catch_all_handler(
 }
 
 #[tracing::instrument(skip(jar))]
-pub async fn root_handler(
-    Query(params): Query<Vec<(String, String)>>,
-    jar: CookieJar,
-) -> impl IntoResponse {
-    if let Some(client_id) = jar.get("client_id") {
+pub async fn root_handler(uri: Uri, jar: CookieJar) -> impl IntoResponse {
+    if let Some(client_id) = jar.get(CLIENT_ID_COOKIE) {
         let client_id = client_id.value();
         let path = format!("/proxy/{client_id}");
 
-        let qs = util::get_query(params);
-        let url = format!("{path}{qs}");
+        let url = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
 
         debug!("client_id: {client_id} incoming path: / redirecting to: {url}");
         Redirect::temporary(url.as_str()).into_response()
@@ -88,3 +115,62 @@ pub async fn root_handler(
         StatusCode::NOT_FOUND.into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backplane::{self, Backplane, InMemoryBackplane},
+        state::ClientConfig,
+    };
+    use axum_extra::extract::cookie::Cookie;
+    use rusty_relay_messages::{ClientOptions, Protection};
+
+    async fn request(state: &Arc<AppState>, client_id: &str) -> StatusCode {
+        catch_all_handler(
+            State(state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))),
+            None,
+            HeaderMap::new(),
+            Method::GET,
+            Uri::from_static("/api/orders"),
+            CookieJar::new().add(Cookie::new(CLIENT_ID_COOKIE, client_id.to_string())),
+            Bytes::new(),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn test_protected_client_on_other_instance() {
+        let backplane_a = InMemoryBackplane::new("a".to_string());
+        let backplane_b = Arc::new(backplane_a.join("b".to_string()));
+        let state_a = Arc::new(AppState::with_backplane(Arc::new(backplane_a)));
+        let state_b = Arc::new(AppState::with_backplane(backplane_b.clone()));
+        backplane::start(state_a.clone()).await.unwrap();
+        backplane::start(state_b.clone()).await.unwrap();
+
+        // the client is connected to instance b, the caller to instance a
+        let options = ClientOptions {
+            protection: Some(Protection {
+                bearer_token: Some("secret".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let config = ClientConfig::try_from(options.clone()).unwrap();
+        let (_client, mut rx) = state_b.add_client("shop", false, config).await.unwrap();
+        assert_eq!(request(&state_a, "shop").await, StatusCode::UNAUTHORIZED);
+
+        // without a valid config the protection can't be checked, so nothing is relayed
+        let invalid = ClientOptions {
+            proxy_timeout: Some(0),
+            ..options
+        };
+        backplane_b.register("shop", &invalid).await.unwrap();
+        assert_eq!(request(&state_a, "shop").await, StatusCode::BAD_REQUEST);
+        assert_eq!(request(&state_a, "cart").await, StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,12 +1,52 @@
 use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    Json,
+    http::{
+        HeaderMap, StatusCode,
+        header::{ACCEPT, RETRY_AFTER, WWW_AUTHENTICATE},
+    },
+    response::{Html, IntoResponse, Response},
 };
+use rusty_relay_messages::ProxyErrorKind;
+use serde_json::json;
+use std::time::Duration;
 
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
 pub enum HttpError {
     BadRequest(String),
     GatewayTimeout(String),
+    BadGateway(String),
     Unauthorized(String),
+    Conflict(String),
+    Forbidden(String),
+    BasicAuthRequired(String),
+    TooManyRequests(Duration),
+    ServiceUnavailable(String),
+    /// The client could not get a response from its local target
+    Target {
+        kind: ProxyErrorKind,
+        message: String,
+        format: ErrorFormat,
+    },
+}
+
+/// How an error page is rendered, browsers get HTML and everything else JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Html,
+    Json,
+}
+
+impl From<&HeaderMap> for ErrorFormat {
+    fn from(headers: &HeaderMap) -> Self {
+        let accepts_html = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+
+        if accepts_html { Self::Html } else { Self::Json }
+    }
 }
 
 impl IntoResponse for HttpError {
@@ -14,7 +54,109 @@ impl IntoResponse for HttpError {
         match self {
             HttpError::BadRequest(m) => (StatusCode::BAD_REQUEST, m).into_response(),
             HttpError::GatewayTimeout(m) => (StatusCode::GATEWAY_TIMEOUT, m).into_response(),
+            HttpError::BadGateway(m) => (StatusCode::BAD_GATEWAY, m).into_response(),
             HttpError::Unauthorized(m) => (StatusCode::UNAUTHORIZED, m).into_response(),
+            HttpError::Conflict(m) => (StatusCode::CONFLICT, m).into_response(),
+            HttpError::Forbidden(m) => (StatusCode::FORBIDDEN, m).into_response(),
+            HttpError::BasicAuthRequired(m) => (
+                StatusCode::UNAUTHORIZED,
+                [(
+                    WWW_AUTHENTICATE,
+                    r#"Basic realm="rusty-relay", charset="UTF-8""#,
+                )],
+                m,
+            )
+                .into_response(),
+            HttpError::TooManyRequests(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())],
+                "Too many requests",
+            )
+                .into_response(),
+            HttpError::ServiceUnavailable(m) => {
+                (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, "1")], m).into_response()
+            }
+            HttpError::Target {
+                kind,
+                message,
+                format,
+            } => target_error_page(kind, &message, format),
+        }
+    }
+}
+
+fn target_error_page(kind: ProxyErrorKind, message: &str, format: ErrorFormat) -> Response {
+    let (status, hint) = match kind {
+        ProxyErrorKind::TargetUnreachable => (
+            StatusCode::BAD_GATEWAY,
+            "The relay client could not reach its local target, make sure it is running.",
+        ),
+        ProxyErrorKind::TargetTimeout => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "The local target of the relay client did not respond in time.",
+        ),
+        ProxyErrorKind::InvalidRequest => (
+            StatusCode::BAD_REQUEST,
+            "The relay client could not send this request to its local target.",
+        ),
+    };
+
+    match format {
+        ErrorFormat::Json => (
+            status,
+            Json(json!({ "error": kind, "message": message, "hint": hint })),
+        )
+            .into_response(),
+        ErrorFormat::Html => {
+            let title = format!(
+                "{} {}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default()
+            );
+            let page = format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n\
+                 <h1>{title}</h1>\n<p>{hint}</p>\n<pre>{}</pre>\n<hr>\n<small>rusty-relay</small>\n\
+                 </body>\n</html>\n",
+                escape_html(message)
+            );
+            (status, Html(page)).into_response()
         }
     }
 }
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_target_error_page() {
+        let error = |format| HttpError::Target {
+            kind: ProxyErrorKind::TargetUnreachable,
+            message: "tcp connect error: <refused>".to_string(),
+            format,
+        };
+
+        let response = error(ErrorFormat::Json).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "target_unreachable");
+
+        let response = error(ErrorFormat::Html).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<h1>502 Bad Gateway</h1>"));
+        assert!(body.contains("&lt;refused&gt;"));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::util;
+use axum::http::{
+    HeaderMap, HeaderName, HeaderValue,
+    header::{CONNECTION, COOKIE, FORWARDED, HOST},
+};
+use ipnet::IpNet;
+use rusty_relay_messages::Headers;
+use std::net::{IpAddr, SocketAddr};
+
+/// Headers that only apply to a single connection (RFC 7230 section 6.1), these are never relayed.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Name of the cookie that makes the catch all route find the client, not meant for the target.
+pub const CLIENT_ID_COOKIE: &str = "client_id";
+
+/// How the caller reached the server, passed on to the local target in the
+/// `X-Forwarded-*` and `Forwarded` headers.
+#[derive(Debug)]
+pub struct Forwarded {
+    pub caller_ip: IpAddr,
+    forwarded_for: String,
+    proto: String,
+    host: Option<String>,
+}
+
+impl Forwarded {
+    /// The proto and host set by a trusted (reverse) proxy are kept, like the caller IP.
+    pub fn new(
+        peer: SocketAddr,
+        headers: &HeaderMap,
+        trusted_proxies: &[IpNet],
+        tls: bool,
+    ) -> Self {
+        let peer_ip = peer.ip().to_canonical();
+        let from_trusted_proxy = trusted_proxies.iter().any(|net| net.contains(&peer_ip));
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| {
+                    value
+                        .split(',')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                })
+        };
+        let forwarded = |name: &str| header(name).filter(|_| from_trusted_proxy);
+
+        let proto = forwarded(X_FORWARDED_PROTO)
+            .unwrap_or_else(|| if tls { "https" } else { "http" }.to_string());
+        let host = forwarded(X_FORWARDED_HOST).or_else(|| header(HOST.as_str()));
+        let caller_ip = util::caller_ip(peer, headers, trusted_proxies);
+        let forwarded_for = if from_trusted_proxy {
+            forwarded_for_chain(headers, caller_ip, peer_ip)
+        } else {
+            caller_ip.to_string()
+        };
+
+        Self {
+            caller_ip,
+            forwarded_for,
+            proto,
+            host,
+        }
+    }
+
+    /// Prepares the headers of a request for the local target: the hop-by-hop headers and the
+    /// relay cookie are removed, the `Host` is left to the client (it becomes the target) and
+    /// the forwarding headers are replaced (a trusted `X-Forwarded-For` is extended).
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        remove_hop_by_hop(headers);
+        remove_client_id_cookie(headers);
+        headers.remove(HOST);
+
+        let for_ip = match self.caller_ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{ip}]\""),
+        };
+        let mut forwarded = format!("for={for_ip};proto={}", self.proto);
+        if let Some(host) = &self.host {
+            forwarded.push_str(&format!(";host=\"{host}\""));
+        }
+
+        let mut insert = |name: HeaderName, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        insert(
+            HeaderName::from_static(X_FORWARDED_FOR),
+            &self.forwarded_for,
+        );
+        insert(HeaderName::from_static(X_FORWARDED_PROTO), &self.proto);
+        insert(FORWARDED, &forwarded);
+        match &self.host {
+            Some(host) => insert(HeaderName::from_static(X_FORWARDED_HOST), host),
+            None => {
+                headers.remove(X_FORWARDED_HOST);
+            }
+        }
+    }
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    for name in HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+/// The incoming `X-Forwarded-For` from the caller on with the trusted proxy appended, the
+/// entries before the caller were added by the caller itself and can't be trusted.
+fn forwarded_for_chain(headers: &HeaderMap, caller_ip: IpAddr, peer_ip: IpAddr) -> String {
+    let chain: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .collect();
+    let start = chain
+        .iter()
+        .rposition(|ip| ip.parse::<IpAddr>().is_ok_and(|ip| ip == caller_ip))
+        .unwrap_or_default();
+
+    let peer_ip = peer_ip.to_string();
+    chain[start..]
+        .iter()
+        .copied()
+        .chain([peer_ip.as_str()])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Removes only the `client_id` pair, any other cookie (even if it isn't UTF-8) is kept as is.
+fn remove_client_id_cookie(headers: &mut HeaderMap) {
+    let values: Vec<HeaderValue> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| {
+            let pairs = value.as_bytes().split(|b| *b == b';');
+            if !pairs.clone().any(is_client_id_cookie) {
+                return Some(value.clone());
+            }
+
+            let retained = pairs
+                .map(<[u8]>::trim_ascii)
+                .filter(|pair| !pair.is_empty() && !is_client_id_cookie(pair))
+                .collect::<Vec<_>>();
+            if retained.is_empty() {
+                return None;
+            }
+            HeaderValue::from_bytes(&retained.join(&b"; "[..])).ok()
+        })
+        .collect();
+
+    headers.remove(COOKIE);
+    for value in values {
+        headers.append(COOKIE, value);
+    }
+}
+
+fn is_client_id_cookie(pair: &[u8]) -> bool {
+    let name = pair.split(|b| *b == b'=').next().unwrap_or_default();
+    name.trim_ascii() == CLIENT_ID_COOKIE.as_bytes()
+}
+
+/// Removes the hop-by-hop headers from the response of the local target.
+pub fn retain_end_to_end(headers: &mut Headers) {
+    let listed: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(CONNECTION.as_str()))
+        .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP.contains(&name.as_str()) && !listed.contains(&name)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "relay.example.com".parse().unwrap());
+        headers.insert(CONNECTION, "keep-alive, x-secret".parse().unwrap());
+        headers.insert("x-secret", "hop".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert(COOKIE, "a=1; client_id=shop; b=2".parse().unwrap());
+        headers.insert(X_FORWARDED_FOR, "6.6.6.6".parse().unwrap());
+        headers.insert("accept", "text/html".parse().unwrap());
+
+        let forwarded = Forwarded::new("1.2.3.4:5678".parse().unwrap(), &headers, &[], true);
+        forwarded.apply(&mut headers);
+
+        assert_eq!(headers.get(COOKIE).unwrap(), "a=1; b=2");
+        assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(), "1.2.3.4");
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "https");
+        assert_eq!(headers.get(X_FORWARDED_HOST).unwrap(), "relay.example.com");
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=1.2.3.4;proto=https;host=\"relay.example.com\""
+        );
+        assert_eq!(headers.get("accept").unwrap(), "text/html");
+        for name in ["host", "connection", "x-secret", "transfer-encoding"] {
+            assert!(headers.get(name).is_none(), "{name} should be removed");
+        }
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "6.6.6.6, 1.2.3.4".parse().unwrap());
+        headers.append(X_FORWARDED_FOR, "10.0.0.5".parse().unwrap());
+        headers.insert(X_FORWARDED_PROTO, "https".parse().unwrap());
+        headers.insert(X_FORWARDED_HOST, "relay.example.com".parse().unwrap());
+        headers.insert(COOKIE, "client_id=shop".parse().unwrap());
+
+        let forwarded = Forwarded::new("10.0.0.1:80".parse().unwrap(), &headers, &trusted, false);
+        forwarded.apply(&mut headers);
+
+        // the chain is kept from the caller on, what the caller claims before that is dropped
+        assert_eq!(forwarded.caller_ip.to_string(), "1.2.3.4");
+        assert_eq!(
+            headers.get(X_FORWARDED_FOR).unwrap(),
+            "1.2.3.4, 10.0.0.5, 10.0.0.1"
+        );
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "https");
+        assert_eq!(headers.get(X_FORWARDED_HOST).unwrap(), "relay.example.com");
+        assert!(headers.get(COOKIE).is_none());
+
+        // not from a trusted proxy, so its forwarding headers are not believed
+        let forwarded = Forwarded::new("9.9.9.9:80".parse().unwrap(), &headers, &trusted, false);
+        assert_eq!(forwarded.caller_ip.to_string(), "9.9.9.9");
+        assert_eq!(forwarded.proto, "http");
+    }
+
+    #[test]
+    fn test_remove_client_id_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, "a=1;b=2".parse().unwrap());
+        headers.append(COOKIE, "client_id=shop".parse().unwrap());
+        headers.append(
+            COOKIE,
+            HeaderValue::from_bytes(b"name=caf\xe9; client_id=shop").unwrap(),
+        );
+        headers.append(COOKIE, HeaderValue::from_bytes(b"name=caf\xe9").unwrap());
+        remove_client_id_cookie(&mut headers);
+
+        let cookies: Vec<&[u8]> = headers
+            .get_all(COOKIE)
+            .iter()
+            .map(|v| v.as_bytes())
+            .collect();
+        assert_eq!(cookies, [&b"a=1;b=2"[..], b"name=caf\xe9", b"name=caf\xe9"]);
+    }
+
+    #[test]
+    fn test_retain_end_to_end() {
+        let mut headers: Headers = vec![
+            ("Connection".to_string(), b"close, x-internal".to_vec()),
+            ("x-internal".to_string(), b"1".to_vec()),
+            ("Transfer-Encoding".to_string(), b"chunked".to_vec()),
+            ("set-cookie".to_string(), b"a=1".to_vec()),
+        ];
+        retain_end_to_end(&mut headers);
+
+        assert_eq!(headers, vec![("set-cookie".to_string(), b"a=1".to_vec())]);
+    }
+}
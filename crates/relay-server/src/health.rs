@@ -1,8 +1,28 @@
-use axum::{Json, response::IntoResponse};
+use crate::state::AppState;
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
 use serde_json::json;
+use std::sync::Arc;
+
+pub async fn health_handler(State(state): State<Arc<AppState>>) -> Response {
+    // without the backplane subscription requests for clients on this instance are lost
+    if !state.backplane().is_healthy() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "DOWN",
+                "backplane": "DOWN"
+            })),
+        )
+            .into_response();
+    }
 
-pub async fn health_handler() -> impl IntoResponse {
     Json(json!({
         "status": "UP"
     }))
+    .into_response()
 }
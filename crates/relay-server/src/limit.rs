@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Buckets are pruned once there are more keys than this, to bound memory for many source IPs.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket rate limiter keyed by e.g. client id or source IP, allowing a burst of one second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// A limit of `0` requests per second disables the limiter.
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long to wait for the next one.
+    pub async fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now()).await
+    }
+
+    async fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        if self.per_second == 0.0 {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().await;
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.per_second);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.per_second,
+            updated_at: now,
+        });
+
+        self.available(bucket, now)?;
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Checks whether `key` has a token left without taking it.
+    pub async fn peek(&self, key: &str) -> Result<(), Duration> {
+        self.peek_at(key, Instant::now()).await
+    }
+
+    async fn peek_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        if self.per_second == 0.0 {
+            return Ok(());
+        }
+
+        match self.buckets.lock().await.get_mut(key) {
+            Some(bucket) => self.available(bucket, now),
+            None => Ok(()),
+        }
+    }
+
+    fn available(&self, bucket: &mut Bucket, now: Instant) -> Result<(), Duration> {
+        if self.refill(bucket, now) >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now
+            .saturating_duration_since(bucket.updated_at)
+            .as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second);
+        bucket.updated_at = now;
+        bucket.tokens
+    }
+}
+
+/// Counts the pending proxy requests of a client.
+#[derive(Debug, Clone, Default)]
+pub struct Pending(Arc<AtomicUsize>);
+
+/// Releases the pending slot when dropped.
+pub struct PendingGuard(Arc<AtomicUsize>);
+
+impl Pending {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn try_acquire(&self, max: usize) -> Option<PendingGuard> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < max).then_some(pending + 1)
+            })
+            .ok()
+            .map(|_| PendingGuard(self.0.clone()))
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", now).await.is_ok());
+        assert!(limiter.check_at("a", now).await.is_ok());
+        assert!(limiter.check_at("a", now).await.is_err());
+        assert!(limiter.check_at("b", now).await.is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at("a", later).await.is_ok());
+        assert!(limiter.check_at("a", later).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_peek() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+
+        assert!(limiter.peek_at("a", now).await.is_ok());
+        assert!(limiter.peek_at("a", now).await.is_ok());
+        assert!(limiter.check_at("a", now).await.is_ok());
+        assert!(limiter.peek_at("a", now).await.is_err());
+        assert!(limiter.buckets.lock().await.get("b").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_disabled() {
+        let limiter = RateLimiter::new(0);
+        for _ in 0..100 {
+            assert!(limiter.check("a").await.is_ok());
+        }
+    }
+
+    #[test]
+    fn test_pending() {
+        let pending = Pending::default();
+
+        let first = pending.try_acquire(2);
+        let second = pending.try_acquire(2);
+        assert!(first.is_some() && second.is_some());
+        assert!(pending.try_acquire(2).is_none());
+
+        drop(first);
+        assert!(pending.try_acquire(2).is_some());
+    }
+}
@@ -1,22 +1,35 @@
 #![allow(clippy::collapsible_if)]
 
-use crate::{state::AppState, util::from_env_or_else};
-use axum::{Router, routing};
+use crate::{acme::Acme, error::BoxError, state::AppState, util::from_env_or_else};
+#[cfg(unix)]
+use axum::{Extension, extract::ConnectInfo};
+use axum::{Router, extract::DefaultBodyLimit, routing};
 use std::{net::SocketAddr, sync::Arc};
 use tracing::info;
 
+mod access;
+mod acme;
+mod backplane;
 mod catch_all;
 mod error;
+mod forwarded;
 mod health;
+mod limit;
 mod proxy;
+mod signature;
 mod state;
 mod tls;
 mod util;
 mod webhook;
 mod websocket;
 
+/// There is no peer address on a unix socket, callers are taken from X-Forwarded-For instead.
+#[cfg(unix)]
+const UNIX_SOCKET_PEER: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), BoxError> {
     tracing_subscriber::fmt::init();
     tls::init();
 
@@ -25,7 +38,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         from_env_or_else("VERSION", || "0.0.0".to_string())
     );
 
-    let state = Arc::new(AppState::new());
+    #[allow(unused_mut)]
+    let mut state = AppState::with_backplane(backplane::from_env().await?);
+
+    #[cfg(unix)]
+    let unix_socket = std::env::var("RUSTY_RELAY_UNIX_SOCKET").ok();
+    // callers come in through the local reverse proxy, which tells who they are
+    #[cfg(unix)]
+    if unix_socket.is_some() {
+        state.trust_proxy(UNIX_SOCKET_PEER.ip().into());
+    }
+
+    let state = Arc::new(state);
+    backplane::start(state.clone()).await?;
 
     let router = Router::new()
         .route("/connect", routing::any(websocket::connect_handler))
@@ -48,9 +73,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/health", routing::get(health::health_handler))
         .route("/{*path}", routing::any(catch_all::catch_all_handler))
         .route("/", routing::any(catch_all::root_handler))
+        .layer(DefaultBodyLimit::max(from_env_or_else(
+            "RUSTY_RELAY_MAX_BODY_SIZE",
+            || 10 * 1024 * 1024,
+        )))
         .with_state(state.clone());
 
-    if let Some(tls_config) = tls::config().await {
+    #[cfg(unix)]
+    if let Some(path) = unix_socket {
+        // a stale socket of a previous run would fail the bind, anything else is left alone
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            use std::os::unix::fs::FileTypeExt;
+
+            if !metadata.file_type().is_socket() {
+                return Err(format!("RUSTY_RELAY_UNIX_SOCKET is not a socket: {path}").into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        info!("🚀 server running (http) on unix socket {path}");
+        info!("🔑 connect token: {}", state.connect_token());
+
+        axum::serve(
+            listener,
+            router
+                .layer(Extension(ConnectInfo(UNIX_SOCKET_PEER)))
+                .into_make_service(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let tls_config = match Acme::from_env()? {
+        Some(acme) => Some(acme.start().await?),
+        None => tls::config().await.inspect(tls::watch),
+    };
+
+    if let Some(tls_config) = tls_config {
         let addr = SocketAddr::from((
             [0, 0, 0, 0],
             from_env_or_else("RUSTY_RELAY_HTTPS_PORT", || 8443),
@@ -58,8 +117,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         info!("🚀 server running (https) on https://{addr}/health");
         info!("🔑 connect token: {}", state.connect_token());
 
-        axum_server::bind_rustls(addr, tls_config)
-            .serve(router.into_make_service())
+        axum_server::bind(addr)
+            .acceptor(tls::Acceptor::new(tls_config))
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
     } else {
         let addr = SocketAddr::from((
@@ -69,7 +129,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         info!("🚀 server running (http) on http://{addr}/health");
         info!("🔑 connect token: {}", state.connect_token());
 
-        axum::serve(tokio::net::TcpListener::bind(addr).await?, router).await?
+        axum::serve(
+            tokio::net::TcpListener::bind(addr).await?,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?
     }
 
     Ok(())
@@ -1,12 +1,16 @@
 use crate::{
-    error::HttpError,
-    state::AppState,
+    backplane::{Envelope, remote_connection_id},
+    error::{ErrorFormat, HttpError},
+    forwarded::{self, CLIENT_ID_COOKIE, Forwarded},
+    state::{AppState, Client},
+    tls::ClientIdentity,
     util::{self, generate_id},
 };
 use axum::{
+    Extension,
     body::{Body, Bytes},
-    extract::{Path, Query, State},
-    http::{HeaderMap, Method},
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, Method, Uri},
     response::{IntoResponse, Response},
 };
 use axum_extra::extract::{
@@ -14,67 +18,107 @@ use axum_extra::extract::{
     cookie::{Cookie, Expiration},
 };
 use rusty_relay_messages::RelayMessage;
-use std::{ops::Not, sync::Arc};
-use tokio::sync::oneshot;
-use tracing::info;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::oneshot,
+    time::{self, Instant},
+};
+use tracing::{debug, info, warn};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_handler_with_path(
     state: State<Arc<AppState>>,
-    Path((client_id, path)): Path<(String, String)>,
-    Query(params): Query<Vec<(String, String)>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    Path((client_id, _)): Path<(String, String)>,
+    uri: Uri,
     headers: HeaderMap,
     method: Method,
     body: Bytes,
 ) -> impl IntoResponse {
-    proxy_handler(state, client_id, Some(path), headers, method, body, params).await
+    // the tls acceptor adds a client identity to every https request
+    let forwarded = Forwarded::new(addr, &headers, state.trusted_proxies(), identity.is_some());
+    // forward the path as received, the extracted one is percent-decoded
+    let path = util::raw_path(&uri, 2).to_string();
+    let query = uri.query().map(str::to_string);
+    proxy_handler(
+        state,
+        forwarded,
+        client_id,
+        Some(path),
+        headers,
+        method,
+        body,
+        query,
+    )
+    .await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_handler_without_path(
     state: State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
     Path(client_id): Path<String>,
-    Query(params): Query<Vec<(String, String)>>,
+    uri: Uri,
     headers: HeaderMap,
     method: Method,
     body: Bytes,
 ) -> impl IntoResponse {
-    proxy_handler(state, client_id, None, headers, method, body, params).await
+    let forwarded = Forwarded::new(addr, &headers, state.trusted_proxies(), identity.is_some());
+    let query = uri.query().map(str::to_string);
+    proxy_handler(
+        state, forwarded, client_id, None, headers, method, body, query,
+    )
+    .await
 }
 
-#[tracing::instrument(skip(state))]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(state, headers))]
 pub async fn proxy_handler(
     state: State<Arc<AppState>>,
+    forwarded: Forwarded,
     client_id: String,
     path: Option<String>,
-    headers: HeaderMap,
+    mut headers: HeaderMap,
     method: Method,
     body: Bytes,
-    params: Vec<(String, String)>,
+    query: Option<String>,
 ) -> impl IntoResponse {
     let request_id = generate_id(20);
     info!(request_id, "🖥 proxy request received");
 
-    if let Some(sender) = state.get_client(&client_id).await {
-        let query = params.is_empty().not().then(|| util::get_query(params));
-        let _ = sender.send(RelayMessage::ProxyRequest {
-            request_id: request_id.clone(),
-            path,
-            query,
-            method: method.to_string(),
-            headers: util::into_hashmap(headers),
-            body: body.to_vec(),
-        });
-    } else {
-        return ProxyResponse::new(
-            CookieJar::default(),
-            HttpError::BadRequest(format!("Client id is unknown: {}", client_id)),
-        );
+    let config = match state.client_config(&client_id).await {
+        Ok(config) => config,
+        Err(err) => return ProxyResponse::new(CookieJar::default(), err),
+    };
+
+    let caller_ip = forwarded.caller_ip;
+    if let Err(err) = state.check_rate_limit(&client_id, caller_ip).await {
+        warn!(request_id, %caller_ip, "❌ proxy request rate limited");
+        return ProxyResponse::new(CookieJar::default(), err);
     }
 
-    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = config.access.check(&mut headers, caller_ip) {
+        debug!(request_id, %caller_ip, "❌ proxy request denied");
+        return ProxyResponse::new(CookieJar::default(), err);
+    }
 
-    state.add_proxy_request(&request_id, resp_tx).await;
+    let format = ErrorFormat::from(&headers);
+    forwarded.apply(&mut headers);
+    let timeout = config
+        .proxy_timeout(path.as_deref())
+        .unwrap_or(state.proxy_timeout());
+    let request = RelayMessage::ProxyRequest {
+        request_id: request_id.clone(),
+        path,
+        query,
+        method: method.to_string(),
+        headers: util::into_headers(headers),
+        body: body.to_vec(),
+    };
 
-    let client_id_cookie = Cookie::build(("client_id", client_id.clone()))
+    let client_id_cookie = Cookie::build((CLIENT_ID_COOKIE, client_id.clone()))
         .expires(Expiration::Session)
         .path("/")
         .http_only(true)
@@ -82,28 +126,246 @@ pub async fn proxy_handler(
 
     let cookie_jar = CookieJar::new().add(client_id_cookie);
 
-    match tokio::time::timeout(state.proxy_timeout(), resp_rx).await {
-        Ok(Ok(RelayMessage::ProxyResponse {
+    match forward(&state, &client_id, &request_id, request, timeout).await {
+        Ok(RelayMessage::ProxyResponse {
             body,
-            headers,
+            mut headers,
             status,
             ..
-        })) => {
+        }) => {
+            forwarded::retain_end_to_end(&mut headers);
             let mut response = axum::response::Response::builder().status(status);
             for (k, v) in headers.iter().filter(|(k, _)| *k != "content-length") {
-                response = response.header(k, v);
+                response = response.header(k, v.as_slice());
             }
 
             ProxyResponse::new(
                 cookie_jar,
                 response
                     .body(Body::from(body))
-                    .map_err(|e| HttpError::BadRequest(e.to_string())),
+                    .map_err(|e| HttpError::BadGateway(e.to_string())),
+            )
+        }
+        Ok(RelayMessage::ProxyError { kind, message, .. }) => ProxyResponse::new(
+            cookie_jar,
+            HttpError::Target {
+                kind,
+                message,
+                format,
+            },
+        ),
+        Ok(_) => ProxyResponse::new(
+            cookie_jar,
+            HttpError::BadGateway("Unexpected response".to_string()),
+        ),
+        Err(err) => ProxyResponse::new(cookie_jar, err),
+    }
+}
+
+/// Sends the proxy request to a client connected to this instance, or otherwise to the
+/// instance the client is connected to via the backplane.
+pub async fn forward(
+    state: &Arc<AppState>,
+    client_id: &str,
+    request_id: &str,
+    request: RelayMessage,
+    timeout: Duration,
+) -> Result<RelayMessage, HttpError> {
+    if !state.get_clients(client_id).await.is_empty() {
+        return forward_local(state, client_id, request_id, request, timeout).await;
+    }
+
+    match state.backplane().locate(client_id).await {
+        Ok(Some(location)) => {
+            forward_remote(
+                state,
+                &location.instance_id,
+                client_id,
+                request_id,
+                request,
+                timeout,
             )
+            .await
+        }
+        Ok(None) => Err(HttpError::BadRequest(format!(
+            "Client id is unknown: {}",
+            client_id
+        ))),
+        Err(err) => {
+            warn!(request_id, "failed to locate client on backplane: {err}");
+            Err(HttpError::ServiceUnavailable(
+                "Backplane is unavailable".to_string(),
+            ))
+        }
+    }
+}
+
+/// Sends the proxy request (or webhook) to the instance the client is connected to and
+/// awaits the response.
+pub async fn forward_remote(
+    state: &Arc<AppState>,
+    instance_id: &str,
+    client_id: &str,
+    request_id: &str,
+    request: RelayMessage,
+    timeout: Duration,
+) -> Result<RelayMessage, HttpError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    state
+        .add_proxy_request(request_id, &remote_connection_id(instance_id), resp_tx)
+        .await;
+
+    let webhook_id =
+        matches!(request, RelayMessage::Webhook { .. }).then(|| request_id.to_string());
+    let envelope = Envelope::Deliver {
+        reply_to: state.backplane().instance_id().to_string(),
+        client_id: client_id.to_string(),
+        message: request,
+        webhook_id,
+    };
+    if let Err(err) = state.backplane().publish(instance_id, envelope).await {
+        warn!(
+            request_id,
+            instance_id, "failed to publish proxy request on backplane: {err}"
+        );
+        state.remove_proxy_request(request_id).await;
+        return Err(HttpError::ServiceUnavailable(
+            "Backplane is unavailable".to_string(),
+        ));
+    }
+
+    let mut in_flight = InFlight {
+        state: state.clone(),
+        request_id: request_id.to_string(),
+        target: Some(Target::Instance(instance_id.to_string())),
+    };
+
+    match time::timeout(timeout, resp_rx).await {
+        Ok(Ok(response)) => {
+            in_flight.finish();
+            Ok(response)
+        }
+        Ok(Err(_)) => {
+            in_flight.finish();
+            Err(HttpError::BadGateway("Client disconnected".to_string()))
+        }
+        Err(_) => Err(HttpError::GatewayTimeout("Timeout".to_string())),
+    }
+}
+
+/// Sends the proxy request to one of the clients connected to this instance and awaits the
+/// response, failing over to the next client when the selected one disconnects.
+pub async fn forward_local(
+    state: &Arc<AppState>,
+    client_id: &str,
+    request_id: &str,
+    request: RelayMessage,
+    timeout: Duration,
+) -> Result<RelayMessage, HttpError> {
+    let deadline = Instant::now() + timeout;
+    let mut tried = Vec::new();
+    let mut saturated = false;
+
+    while let Some(client) = state.select_client(client_id, &tried).await {
+        // another client with the same name may still have room
+        let Some(_pending) = client.pending.try_acquire(state.max_pending_requests()) else {
+            debug!(request_id, "client has too many pending proxy requests");
+            saturated = true;
+            tried.push(client.connection_id);
+            continue;
+        };
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state
+            .add_proxy_request(request_id, &client.connection_id, resp_tx)
+            .await;
+        if let Err(err) = client.send(request.clone()) {
+            state.remove_proxy_request(request_id).await;
+            return Err(err);
+        }
+
+        let mut in_flight = InFlight {
+            state: state.clone(),
+            request_id: request_id.to_string(),
+            target: Some(Target::Client(client.clone())),
+        };
+
+        match time::timeout_at(deadline, resp_rx).await {
+            Ok(Ok(response)) => {
+                in_flight.finish();
+                return Ok(response);
+            }
+            Ok(Err(_)) => {
+                in_flight.finish();
+                info!(request_id, "🔀 client disconnected, failing over");
+                tried.push(client.connection_id);
+            }
+            Err(_) => return Err(HttpError::GatewayTimeout("Timeout".to_string())),
         }
-        _ => ProxyResponse::new(cookie_jar, HttpError::GatewayTimeout("Timeout".to_string())),
     }
+
+    Err(if tried.is_empty() {
+        HttpError::BadRequest(format!("Client id is unknown: {}", client_id))
+    } else if saturated {
+        warn!(request_id, "❌ too many pending proxy requests");
+        HttpError::ServiceUnavailable("Too many pending requests".to_string())
+    } else {
+        HttpError::BadGateway("Client disconnected".to_string())
+    })
+}
+
+/// A proxy request sent to a client or another instance, when dropped before it is finished
+/// (timeout or the caller went away) it is removed and the client is told to cancel it.
+struct InFlight {
+    state: Arc<AppState>,
+    request_id: String,
+    target: Option<Target>,
 }
+
+enum Target {
+    Client(Client),
+    /// The instance the client is connected to, which relays the cancel
+    Instance(String),
+}
+
+impl InFlight {
+    fn finish(&mut self) {
+        self.target = None;
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let Some(target) = self.target.take() else {
+            return;
+        };
+
+        debug!(request_id = self.request_id, "🚫 proxy request abandoned");
+        let state = self.state.clone();
+        let request_id = std::mem::take(&mut self.request_id);
+        match target {
+            Target::Client(client) => {
+                client.cancel(&request_id);
+                tokio::spawn(async move { state.remove_proxy_request(&request_id).await });
+            }
+            Target::Instance(instance_id) => {
+                tokio::spawn(async move {
+                    state.remove_proxy_request(&request_id).await;
+                    let envelope = Envelope::Cancel {
+                        request_id: request_id.clone(),
+                    };
+                    if let Err(err) = state.backplane().publish(&instance_id, envelope).await {
+                        warn!(
+                            request_id,
+                            instance_id, "failed to publish cancel on backplane: {err}"
+                        );
+                    }
+                });
+            }
+        }
+    }
+}
+
 struct ProxyResponse {
     cookie_jar: CookieJar,
     response: Response,
@@ -123,3 +385,162 @@ impl IntoResponse for ProxyResponse {
         (self.cookie_jar, self.response).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backplane::{self, InMemoryBackplane},
+        state::ClientConfig,
+    };
+
+    fn proxy_request(request_id: &str) -> RelayMessage {
+        RelayMessage::ProxyRequest {
+            request_id: request_id.to_string(),
+            method: "GET".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            path: None,
+            query: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_saturated_client_is_skipped() {
+        let state = Arc::new(AppState::new());
+        let (busy, mut busy_rx) = state
+            .add_client("shop", true, ClientConfig::default())
+            .await
+            .unwrap();
+        let (idle, mut idle_rx) = state
+            .add_client("shop", true, ClientConfig::default())
+            .await
+            .unwrap();
+
+        let max = state.max_pending_requests();
+        let _pending = (0..max)
+            .map(|_| busy.pending.try_acquire(max).unwrap())
+            .collect::<Vec<_>>();
+
+        let timeout = Duration::from_millis(50);
+        for request_id in ["request-1", "request-2"] {
+            let result = forward_local(
+                &state,
+                "shop",
+                request_id,
+                proxy_request(request_id),
+                timeout,
+            );
+            assert!(matches!(result.await, Err(HttpError::GatewayTimeout(_))));
+        }
+        assert!(busy_rx.try_recv().is_err());
+        assert!(matches!(
+            idle_rx.try_recv(),
+            Ok(RelayMessage::ProxyRequest { .. })
+        ));
+
+        // only when every client is saturated the request is rejected
+        let _pending = (0..max)
+            .map(|_| idle.pending.try_acquire(max).unwrap())
+            .collect::<Vec<_>>();
+        let result = forward_local(
+            &state,
+            "shop",
+            "request-3",
+            proxy_request("request-3"),
+            timeout,
+        );
+        assert!(matches!(
+            result.await,
+            Err(HttpError::ServiceUnavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_remote_request_is_cancelled() {
+        let backplane_a = InMemoryBackplane::new("a".to_string());
+        let backplane_b = backplane_a.join("b".to_string());
+        let state_a = Arc::new(AppState::with_backplane(Arc::new(backplane_a)));
+        let state_b = Arc::new(AppState::with_backplane(Arc::new(backplane_b)));
+        backplane::start(state_a.clone()).await.unwrap();
+        backplane::start(state_b.clone()).await.unwrap();
+
+        // the client is connected to instance b, the caller to instance a
+        let (_client, mut rx) = state_b
+            .add_client("shop", false, ClientConfig::default())
+            .await
+            .unwrap();
+        let task = tokio::spawn({
+            let state = state_a.clone();
+            async move {
+                let timeout = state.proxy_timeout();
+                forward(
+                    &state,
+                    "shop",
+                    "request-1",
+                    proxy_request("request-1"),
+                    timeout,
+                )
+                .await
+            }
+        });
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(RelayMessage::ProxyRequest { .. })
+        ));
+
+        task.abort();
+        let _ = task.await;
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(RelayMessage::CancelRequest { request_id }) if request_id == "request-1"
+        ));
+        assert!(
+            state_a
+                .take_proxy_request("request-1", &remote_connection_id("b"))
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_request_is_cancelled() {
+        let state = Arc::new(AppState::new());
+        let (client, mut rx) = state
+            .add_client("shop", false, ClientConfig::default())
+            .await
+            .unwrap();
+
+        let request = proxy_request("request-1");
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let timeout = state.proxy_timeout();
+                forward_local(&state, "shop", "request-1", request, timeout).await
+            }
+        });
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(RelayMessage::ProxyRequest { .. })
+        ));
+
+        // the caller went away before the client responded
+        task.abort();
+        let _ = task.await;
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(RelayMessage::CancelRequest { request_id }) if request_id == "request-1"
+        ));
+        tokio::task::yield_now().await;
+        assert!(
+            state
+                .take_proxy_request("request-1", &client.connection_id)
+                .await
+                .is_none()
+        );
+    }
+}
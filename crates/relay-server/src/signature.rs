@@ -0,0 +1,219 @@
+use crate::error::HttpError;
+use axum::http::{HeaderMap, HeaderName};
+use hmac::{Hmac, Mac};
+use rusty_relay_messages::{SignatureProvider, WebhookSignature};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Maximum age (in seconds) of a signed timestamp (stripe/slack) to prevent replays.
+const TIMESTAMP_TOLERANCE: u64 = 300;
+
+/// Verifies webhook signatures with the [`WebhookSignature`] a client configured.
+#[derive(Debug)]
+pub struct SignatureVerifier {
+    provider: SignatureProvider,
+    secret: Vec<u8>,
+    header: HeaderName,
+}
+
+impl TryFrom<WebhookSignature> for SignatureVerifier {
+    type Error = HttpError;
+
+    fn try_from(signature: WebhookSignature) -> Result<Self, Self::Error> {
+        if signature.secret.is_empty() {
+            return Err(HttpError::BadRequest(
+                "Webhook signature secret is empty".to_string(),
+            ));
+        }
+
+        let header = signature.header.as_deref().unwrap_or("X-Signature");
+        let header = HeaderName::try_from(header).map_err(|_| {
+            HttpError::BadRequest(format!("Invalid webhook signature header: {header}"))
+        })?;
+
+        Ok(Self {
+            provider: signature.provider,
+            secret: signature.secret.into_bytes(),
+            header,
+        })
+    }
+}
+
+impl SignatureVerifier {
+    /// Returns the reason why the webhook is rejected, if any.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.verify_at(headers, body, now)
+    }
+
+    fn verify_at(&self, headers: &HeaderMap, body: &[u8], now: u64) -> Result<(), String> {
+        match self.provider {
+            SignatureProvider::Github => {
+                let signature = header(headers, "x-hub-signature-256")?;
+                let signature = signature
+                    .strip_prefix("sha256=")
+                    .ok_or("signature is missing the sha256= prefix")?;
+
+                self.verify_hex(&[body], signature)
+            }
+            SignatureProvider::Stripe => {
+                let header = header(headers, "stripe-signature")?;
+                let mut timestamp = None;
+                let mut signatures = Vec::new();
+                for (key, value) in header.split(',').filter_map(|part| part.split_once('=')) {
+                    match key.trim() {
+                        "t" => timestamp = Some(value.trim()),
+                        "v1" => signatures.push(value.trim()),
+                        _ => {}
+                    }
+                }
+
+                let timestamp = timestamp.ok_or("signature is missing the timestamp")?;
+                check_timestamp(timestamp, now)?;
+
+                let payload = [timestamp.as_bytes(), b".", body];
+                signatures
+                    .iter()
+                    .find(|signature| self.verify_hex(&payload, signature).is_ok())
+                    .map(|_| ())
+                    .ok_or_else(|| "signature does not match".to_string())
+            }
+            SignatureProvider::Slack => {
+                let timestamp = header(headers, "x-slack-request-timestamp")?;
+                check_timestamp(timestamp, now)?;
+
+                let signature = header(headers, "x-slack-signature")?;
+                let signature = signature
+                    .strip_prefix("v0=")
+                    .ok_or("signature is missing the v0= prefix")?;
+
+                self.verify_hex(&[b"v0:", timestamp.as_bytes(), b":", body], signature)
+            }
+            SignatureProvider::Hmac => {
+                let signature = header(headers, self.header.as_str())?;
+                let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+
+                self.verify_hex(&[body], signature)
+            }
+        }
+    }
+
+    fn verify_hex(&self, payload: &[&[u8]], signature: &str) -> Result<(), String> {
+        let signature = hex::decode(signature).map_err(|_| "signature is not hex encoded")?;
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret).map_err(|err| err.to_string())?;
+        for part in payload {
+            mac.update(part);
+        }
+
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature does not match".to_string())
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| format!("header {name} is missing"))
+}
+
+fn check_timestamp(timestamp: &str, now: u64) -> Result<(), String> {
+    let timestamp = timestamp
+        .parse::<u64>()
+        .map_err(|_| format!("timestamp is invalid: {timestamp}"))?;
+
+    if now.abs_diff(timestamp) > TIMESTAMP_TOLERANCE {
+        Err(format!("timestamp is too old: {timestamp}"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const NOW: u64 = 1_700_000_000;
+
+    fn verifier(provider: SignatureProvider) -> SignatureVerifier {
+        SignatureVerifier::try_from(WebhookSignature {
+            provider,
+            secret: SECRET.to_string(),
+            header: None,
+        })
+        .unwrap()
+    }
+
+    fn sign(payload: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(payload);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(headers: &[(&'static str, String)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), v.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_github() {
+        let verifier = verifier(SignatureProvider::Github);
+
+        // example from the github documentation
+        let valid = headers(&[(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17".to_string(),
+        )]);
+        assert!(verifier.verify_at(&valid, BODY, NOW).is_ok());
+        assert!(verifier.verify_at(&valid, b"Hello, World", NOW).is_err());
+        assert!(verifier.verify_at(&HeaderMap::new(), BODY, NOW).is_err());
+    }
+
+    #[test]
+    fn test_stripe() {
+        let verifier = verifier(SignatureProvider::Stripe);
+        let signature = sign(format!("{NOW}.Hello, World!").as_bytes());
+
+        let valid = headers(&[(
+            "stripe-signature",
+            format!("t={NOW},v1=00ff,v1={signature}"),
+        )]);
+        assert!(verifier.verify_at(&valid, BODY, NOW).is_ok());
+        assert!(verifier.verify_at(&valid, BODY, NOW + 301).is_err());
+    }
+
+    #[test]
+    fn test_slack() {
+        let verifier = verifier(SignatureProvider::Slack);
+        let signature = sign(format!("v0:{NOW}:Hello, World!").as_bytes());
+
+        let valid = headers(&[
+            ("x-slack-request-timestamp", NOW.to_string()),
+            ("x-slack-signature", format!("v0={signature}")),
+        ]);
+        assert!(verifier.verify_at(&valid, BODY, NOW).is_ok());
+        assert!(verifier.verify_at(&valid, b"forged", NOW).is_err());
+    }
+
+    #[test]
+    fn test_hmac() {
+        let verifier = verifier(SignatureProvider::Hmac);
+
+        let valid = headers(&[("x-signature", sign(BODY))]);
+        assert!(verifier.verify_at(&valid, BODY, NOW).is_ok());
+
+        let invalid = headers(&[("x-signature", "not-hex".to_string())]);
+        assert!(verifier.verify_at(&invalid, BODY, NOW).is_err());
+    }
+}
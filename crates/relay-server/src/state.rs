@@ -1,14 +1,221 @@
-use crate::util::{from_env_or_else, generate_id};
-use rusty_relay_messages::RelayMessage;
-use std::{collections::HashMap, time::Duration};
-use tokio::sync::{Mutex, broadcast, oneshot};
+use crate::{
+    access::{AccessPolicy, parse_ip_net},
+    backplane::{Backplane, InMemoryBackplane},
+    error::HttpError,
+    limit::{Pending, RateLimiter},
+    signature::SignatureVerifier,
+    util::{from_env_or_else, generate_id},
+};
+use ipnet::IpNet;
+use rusty_relay_messages::{ClientOptions, RelayMessage};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    Mutex,
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tracing::warn;
+
+/// The validated [`ClientOptions`] a client connected with.
+#[derive(Debug, Default)]
+pub struct ClientConfig {
+    pub access: AccessPolicy,
+    pub signature: Option<SignatureVerifier>,
+    options: ClientOptions,
+}
+
+impl TryFrom<ClientOptions> for ClientConfig {
+    type Error = HttpError;
+
+    fn try_from(options: ClientOptions) -> Result<Self, Self::Error> {
+        let timeouts = options.route_timeouts.iter().map(|route| route.timeout);
+        if options
+            .proxy_timeout
+            .into_iter()
+            .chain(timeouts)
+            .any(|timeout| timeout == 0)
+        {
+            return Err(HttpError::BadRequest(
+                "Proxy timeout must be at least 1 second".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            access: options
+                .protection
+                .clone()
+                .map(AccessPolicy::try_from)
+                .transpose()?
+                .unwrap_or_default(),
+            signature: options
+                .webhook_signature
+                .clone()
+                .map(SignatureVerifier::try_from)
+                .transpose()?,
+            options,
+        })
+    }
+}
+
+impl ClientConfig {
+    /// The proxy timeout the client asked for `path`, from the first matching route.
+    pub fn proxy_timeout(&self, path: Option<&str>) -> Option<Duration> {
+        let path = path.unwrap_or_default();
+        self.options
+            .route_timeouts
+            .iter()
+            .find(|route| route.matches(path))
+            .map(|route| route.timeout)
+            .or(self.options.proxy_timeout)
+            .map(Duration::from_secs)
+    }
+
+    /// The longest proxy timeout the client asked for.
+    pub fn max_proxy_timeout(&self) -> Option<Duration> {
+        self.options
+            .route_timeouts
+            .iter()
+            .map(|route| route.timeout)
+            .chain(self.options.proxy_timeout)
+            .max()
+            .map(Duration::from_secs)
+    }
+}
+
+/// A single websocket connection of a client.
+#[derive(Clone)]
+pub struct Client {
+    pub connection_id: String,
+    sender: mpsc::Sender<RelayMessage>,
+    pub pending: Pending,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Client {
+    /// Queues a message for the websocket connection without waiting, a full queue is
+    /// reported to the caller instead of silently dropping the message.
+    pub fn send(&self, message: RelayMessage) -> Result<(), HttpError> {
+        self.sender.try_send(message).map_err(|err| {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            match err {
+                TrySendError::Full(_) => {
+                    warn!(dropped, "❌ client queue is full, message rejected");
+                    HttpError::ServiceUnavailable("Client is overloaded".to_string())
+                }
+                TrySendError::Closed(_) => {
+                    warn!(dropped, "❌ client disconnected, message rejected");
+                    HttpError::ServiceUnavailable("Client is disconnected".to_string())
+                }
+            }
+        })
+    }
+
+    /// Tells the client to stop working on an abandoned proxy request.
+    pub fn cancel(&self, request_id: &str) {
+        let _ = self.sender.try_send(RelayMessage::CancelRequest {
+            request_id: request_id.to_string(),
+        });
+    }
+
+    /// Number of messages that were rejected for this client.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// All clients connected under the same client id, only named clients can share one.
+struct Tunnel {
+    named: bool,
+    config: Arc<ClientConfig>,
+    clients: Vec<Client>,
+    next: usize,
+}
+
+impl Tunnel {
+    fn admit(&self, id: &str, named: bool, config: &ClientConfig) -> Result<(), HttpError> {
+        admit(id, self.named, &self.config.options, named, config)
+    }
+}
+
+/// Only named clients with the same options can join the clients already connected under `id`.
+fn admit(
+    id: &str,
+    joined_named: bool,
+    joined_options: &ClientOptions,
+    named: bool,
+    config: &ClientConfig,
+) -> Result<(), HttpError> {
+    if !(named && joined_named) {
+        return Err(HttpError::Conflict(format!(
+            "Client id is already in use: {id}"
+        )));
+    }
+    if *joined_options != config.options {
+        return Err(HttpError::Conflict(format!(
+            "Client options differ from the other clients named: {id}"
+        )));
+    }
+    Ok(())
+}
+
+/// How proxy requests are distributed across clients sharing a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBalancing {
+    RoundRobin,
+    LeastPending,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-pending" => Ok(Self::LeastPending),
+            _ => Err(format!("unknown load balancing strategy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for LoadBalancing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RoundRobin => f.write_str("round-robin"),
+            Self::LeastPending => f.write_str("least-pending"),
+        }
+    }
+}
+
+struct ProxyRequest {
+    connection_id: String,
+    sender: oneshot::Sender<RelayMessage>,
+}
 
 pub struct AppState {
-    clients: Mutex<HashMap<String, broadcast::Sender<RelayMessage>>>,
-    proxy_requests: Mutex<HashMap<String, oneshot::Sender<RelayMessage>>>,
+    backplane: Arc<dyn Backplane>,
+    tunnels: Mutex<HashMap<String, Tunnel>>,
+    proxy_requests: Mutex<HashMap<String, ProxyRequest>>,
     connect_token: String,
     proxy_timeout: Duration,
+    max_proxy_timeout: Duration,
     ping_interval: Duration,
+    trusted_proxies: Vec<IpNet>,
+    client_rate_limiter: RateLimiter,
+    ip_rate_limiter: RateLimiter,
+    max_pending_requests: usize,
+    client_buffer_size: usize,
+    load_balancing: LoadBalancing,
+    webhook_fanout: bool,
 }
 
 impl Default for AppState {
@@ -19,30 +226,83 @@ impl Default for AppState {
 
 impl AppState {
     pub fn new() -> Self {
+        Self::with_backplane(Arc::new(InMemoryBackplane::new(generate_id(12))))
+    }
+
+    pub fn with_backplane(backplane: Arc<dyn Backplane>) -> Self {
         Self {
-            clients: Mutex::new(HashMap::new()),
+            backplane,
+            tunnels: Mutex::new(HashMap::new()),
             proxy_requests: Mutex::new(HashMap::new()),
             connect_token: from_env_or_else("RUSTY_RELAY_CONNECT_TOKEN", || generate_id(24)),
             proxy_timeout: Duration::from_secs(from_env_or_else("RUSTY_RELAY_PROXY_TIMEOUT", || 5)),
+            max_proxy_timeout: Duration::from_secs(from_env_or_else(
+                "RUSTY_RELAY_MAX_PROXY_TIMEOUT",
+                || 300,
+            )),
             ping_interval: Duration::from_secs(from_env_or_else(
                 "RUSTY_RELAY_PING_INTERVAL",
                 || 25,
             )),
+            trusted_proxies: from_env_or_else("RUSTY_RELAY_TRUSTED_PROXIES", String::new)
+                .split(',')
+                .filter_map(|ip| parse_ip_net(ip.trim()))
+                .collect(),
+            client_rate_limiter: RateLimiter::new(from_env_or_else(
+                "RUSTY_RELAY_CLIENT_RATE_LIMIT",
+                || 0,
+            )),
+            ip_rate_limiter: RateLimiter::new(from_env_or_else("RUSTY_RELAY_IP_RATE_LIMIT", || 0)),
+            max_pending_requests: from_env_or_else("RUSTY_RELAY_MAX_PENDING_REQUESTS", || 100),
+            client_buffer_size: from_env_or_else("RUSTY_RELAY_CLIENT_BUFFER_SIZE", || 100),
+            load_balancing: from_env_or_else("RUSTY_RELAY_LOAD_BALANCING", || {
+                LoadBalancing::RoundRobin
+            }),
+            webhook_fanout: from_env_or_else("RUSTY_RELAY_WEBHOOK_FANOUT", || false),
         }
     }
 
-    pub async fn add_proxy_request(&self, request_id: &str, tx: oneshot::Sender<RelayMessage>) {
-        self.proxy_requests
-            .lock()
-            .await
-            .insert(request_id.to_string(), tx);
+    pub async fn add_proxy_request(
+        &self,
+        request_id: &str,
+        connection_id: &str,
+        sender: oneshot::Sender<RelayMessage>,
+    ) {
+        self.proxy_requests.lock().await.insert(
+            request_id.to_string(),
+            ProxyRequest {
+                connection_id: connection_id.to_string(),
+                sender,
+            },
+        );
+    }
+
+    pub async fn remove_proxy_request(&self, request_id: &str) {
+        self.proxy_requests.lock().await.remove(request_id);
     }
 
-    pub async fn remove_proxy_request(
+    /// Takes the response sender of a proxy request, only the connection it was sent to may answer it.
+    pub async fn take_proxy_request(
         &self,
         request_id: &str,
+        connection_id: &str,
     ) -> Option<oneshot::Sender<RelayMessage>> {
-        self.proxy_requests.lock().await.remove(request_id)
+        let mut proxy_requests = self.proxy_requests.lock().await;
+        match proxy_requests.get(request_id) {
+            Some(request) if request.connection_id == connection_id => proxy_requests
+                .remove(request_id)
+                .map(|request| request.sender),
+            _ => None,
+        }
+    }
+
+    /// Drops the response senders of all proxy requests sent to a connection, so the callers
+    /// can fail over right away.
+    pub async fn remove_proxy_requests(&self, connection_id: &str) {
+        self.proxy_requests
+            .lock()
+            .await
+            .retain(|_, request| request.connection_id != connection_id);
     }
 
     pub fn connect_token(&self) -> &str {
@@ -53,24 +313,345 @@ impl AppState {
         self.proxy_timeout
     }
 
+    /// The longest proxy timeout a client may ask for.
+    pub fn max_proxy_timeout(&self) -> Duration {
+        self.max_proxy_timeout
+    }
+
     pub fn ping_interval(&self) -> Duration {
         self.ping_interval
     }
 
-    pub async fn remove_client(&self, id: &str) {
-        self.clients.lock().await.remove(id);
+    pub fn trusted_proxies(&self) -> &[IpNet] {
+        &self.trusted_proxies
+    }
+
+    pub fn trust_proxy(&mut self, net: IpNet) {
+        if !self.trusted_proxies.contains(&net) {
+            self.trusted_proxies.push(net);
+        }
+    }
+
+    pub fn max_pending_requests(&self) -> usize {
+        self.max_pending_requests
+    }
+
+    /// Takes a token from both the source IP and the client id rate limiter, only for a known
+    /// client id so no buckets are kept for made up ones.
+    pub async fn check_rate_limit(&self, client_id: &str, ip: IpAddr) -> Result<(), HttpError> {
+        let ip = ip.to_string();
+        // a caller over its own limit doesn't get to spend the budget of the client
+        self.ip_rate_limiter
+            .peek(&ip)
+            .await
+            .map_err(HttpError::TooManyRequests)?;
+        self.client_rate_limiter
+            .check(client_id)
+            .await
+            .map_err(HttpError::TooManyRequests)?;
+        self.ip_rate_limiter
+            .check(&ip)
+            .await
+            .map_err(HttpError::TooManyRequests)
+    }
+
+    pub fn backplane(&self) -> &dyn Backplane {
+        self.backplane.as_ref()
+    }
+
+    pub fn webhook_fanout(&self) -> bool {
+        self.webhook_fanout
+    }
+
+    /// Checks whether a client may connect under `id`, only named clients with the
+    /// same options can share it (also with clients connected to other instances).
+    pub async fn can_join(
+        &self,
+        id: &str,
+        named: bool,
+        config: &ClientConfig,
+    ) -> Result<(), HttpError> {
+        if let Some(tunnel) = self.tunnels.lock().await.get(id) {
+            return tunnel.admit(id, named, config);
+        }
+
+        match self.backplane.locate(id).await {
+            Ok(Some(location)) => {
+                // only named clients connect with a name in their options
+                let joined_named = location.options.name.is_some();
+                admit(id, joined_named, &location.options, named, config)
+            }
+            Ok(None) => Ok(()),
+            Err(err) => {
+                warn!(
+                    client_id = id,
+                    "failed to locate client on backplane: {err}"
+                );
+                Err(HttpError::ServiceUnavailable(
+                    "Backplane is unavailable".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Adds a client under `id`, checked again as other clients may have connected since
+    /// [`AppState::can_join`].
+    pub async fn add_client(
+        &self,
+        id: &str,
+        named: bool,
+        config: ClientConfig,
+    ) -> Result<(Client, mpsc::Receiver<RelayMessage>), HttpError> {
+        let mut tunnels = self.tunnels.lock().await;
+        if let Some(tunnel) = tunnels.get(id) {
+            tunnel.admit(id, named, &config)?;
+        }
+
+        let (sender, receiver) = mpsc::channel(self.client_buffer_size.max(1));
+        let client = Client {
+            connection_id: generate_id(12),
+            sender,
+            pending: Pending::default(),
+            dropped: Arc::default(),
+        };
+
+        let tunnel = tunnels.entry(id.to_string()).or_insert_with(|| Tunnel {
+            named,
+            config: Arc::new(config),
+            clients: Vec::new(),
+            next: 0,
+        });
+        tunnel.clients.push(client.clone());
+
+        let options = tunnel.config.options.clone();
+        drop(tunnels);
+
+        if let Err(err) = self.backplane.register(id, &options).await {
+            warn!(
+                client_id = id,
+                "failed to register client on backplane: {err}"
+            );
+        }
+
+        Ok((client, receiver))
     }
 
-    pub async fn get_client(&self, id: &str) -> Option<broadcast::Sender<RelayMessage>> {
-        self.clients.lock().await.get(id).cloned()
+    pub async fn remove_client(&self, id: &str, connection_id: &str) {
+        let mut tunnels = self.tunnels.lock().await;
+        if let Some(tunnel) = tunnels.get_mut(id) {
+            tunnel
+                .clients
+                .retain(|client| client.connection_id != connection_id);
+            if tunnel.clients.is_empty() {
+                tunnels.remove(id);
+                drop(tunnels);
+
+                if let Err(err) = self.backplane.unregister(id).await {
+                    warn!(
+                        client_id = id,
+                        "failed to unregister client on backplane: {err}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// The ids and options of the clients connected to this instance.
+    pub async fn local_clients(&self) -> Vec<(String, ClientOptions)> {
+        self.tunnels
+            .lock()
+            .await
+            .iter()
+            .map(|(id, tunnel)| (id.clone(), tunnel.config.options.clone()))
+            .collect()
     }
 
-    pub async fn add_client(&self, id: &str) -> broadcast::Receiver<RelayMessage> {
-        let mut clients = self.clients.lock().await;
-        let sender = clients
-            .entry(id.to_string())
-            .or_insert_with(|| broadcast::channel(100).0);
+    /// The config of a client connected to this or (via the backplane) another instance,
+    /// a request for a client without a known config must not be relayed.
+    pub async fn client_config(&self, id: &str) -> Result<Arc<ClientConfig>, HttpError> {
+        if let Some(tunnel) = self.tunnels.lock().await.get(id) {
+            return Ok(tunnel.config.clone());
+        }
+
+        let unknown = || HttpError::BadRequest(format!("Client id is unknown: {}", id));
+        match self.backplane.locate(id).await {
+            Ok(Some(location)) => ClientConfig::try_from(location.options)
+                .map(Arc::new)
+                .map_err(|err| {
+                    warn!(
+                        client_id = id,
+                        ?err,
+                        "client options on backplane are invalid"
+                    );
+                    unknown()
+                }),
+            Ok(None) => Err(unknown()),
+            Err(err) => {
+                warn!(
+                    client_id = id,
+                    "failed to locate client on backplane: {err}"
+                );
+                Err(HttpError::ServiceUnavailable(
+                    "Backplane is unavailable".to_string(),
+                ))
+            }
+        }
+    }
+
+    pub async fn get_clients(&self, id: &str) -> Vec<Client> {
+        self.tunnels
+            .lock()
+            .await
+            .get(id)
+            .map(|tunnel| tunnel.clients.clone())
+            .unwrap_or_default()
+    }
+
+    /// Picks one of the clients connected under `id` (skipping `exclude`d connections)
+    /// according to the load balancing strategy.
+    pub async fn select_client(&self, id: &str, exclude: &[String]) -> Option<Client> {
+        let mut tunnels = self.tunnels.lock().await;
+        let tunnel = tunnels.get_mut(id)?;
+        let candidates = tunnel
+            .clients
+            .iter()
+            .filter(|client| !exclude.contains(&client.connection_id))
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let client = match self.load_balancing {
+            LoadBalancing::RoundRobin => candidates[tunnel.next % candidates.len()],
+            LoadBalancing::LeastPending => candidates
+                .iter()
+                .min_by_key(|client| client.pending.count())
+                .copied()?,
+        }
+        .clone();
+        tunnel.next = tunnel.next.wrapping_add(1);
+
+        Some(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_relay_messages::RouteTimeout;
+
+    #[tokio::test]
+    async fn test_client_queue_full() {
+        let state = AppState::new();
+        let (client, _receiver) = state
+            .add_client("test", false, ClientConfig::default())
+            .await
+            .unwrap();
+
+        let webhook = || RelayMessage::Webhook {
+            method: "POST".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        for _ in 0..state.client_buffer_size {
+            assert!(client.send(webhook()).is_ok());
+        }
+        assert!(matches!(
+            client.send(webhook()),
+            Err(HttpError::ServiceUnavailable(_))
+        ));
+        assert_eq!(client.dropped(), 1);
+    }
+
+    #[test]
+    fn test_proxy_timeouts() {
+        let config = ClientConfig::try_from(ClientOptions {
+            proxy_timeout: Some(10),
+            route_timeouts: vec![RouteTimeout {
+                path: "/reports/*".to_string(),
+                timeout: 60,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let timeout = |path| config.proxy_timeout(path).map(|t| t.as_secs());
+        assert_eq!(timeout(Some("reports/2024")), Some(60));
+        assert_eq!(timeout(Some("api/users")), Some(10));
+        assert_eq!(timeout(None), Some(10));
+        assert_eq!(config.max_proxy_timeout(), Some(Duration::from_secs(60)));
+
+        assert!(
+            ClientConfig::default()
+                .proxy_timeout(Some("reports"))
+                .is_none()
+        );
+        assert!(
+            ClientConfig::try_from(ClientOptions {
+                proxy_timeout: Some(0),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_named_clients() {
+        let state = AppState::new();
+        let (first, _rx1) = state
+            .add_client("shop", true, ClientConfig::default())
+            .await
+            .unwrap();
+        let (second, _rx2) = state
+            .add_client("shop", true, ClientConfig::default())
+            .await
+            .unwrap();
+
+        let selected = [
+            state.select_client("shop", &[]).await.unwrap(),
+            state.select_client("shop", &[]).await.unwrap(),
+        ];
+        assert_ne!(selected[0].connection_id, selected[1].connection_id);
+
+        let excluded = [first.connection_id.clone()];
+        let failover = state.select_client("shop", &excluded).await.unwrap();
+        assert_eq!(failover.connection_id, second.connection_id);
+
+        assert!(
+            state
+                .can_join("shop", true, &ClientConfig::default())
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            state
+                .can_join("shop", false, &ClientConfig::default())
+                .await,
+            Err(HttpError::Conflict(_))
+        ));
+
+        // both passed `can_join` before either was added
+        let (_unnamed, _rx3) = state
+            .add_client("cart", false, ClientConfig::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            state
+                .add_client("cart", false, ClientConfig::default())
+                .await,
+            Err(HttpError::Conflict(_))
+        ));
+        assert!(matches!(
+            state
+                .add_client("shop", false, ClientConfig::default())
+                .await,
+            Err(HttpError::Conflict(_))
+        ));
 
-        sender.subscribe()
+        state.remove_client("shop", &first.connection_id).await;
+        state.remove_client("shop", &second.connection_id).await;
+        assert!(state.client_config("shop").await.is_err());
     }
 }
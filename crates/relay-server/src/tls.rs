@@ -1,5 +1,29 @@
-use crate::util::from_env_or_else;
-use axum_server::tls_rustls::RustlsConfig;
+use crate::{error::BoxError, util::from_env_or_else, websocket::is_valid_name};
+use axum::{Extension, middleware::AddExtension};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use rustls::{
+    ConfigBuilder, RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{debug, info, warn};
 
 pub fn init() {
     rustls::crypto::aws_lc_rs::default_provider()
@@ -7,13 +31,237 @@ pub fn init() {
         .expect("cryptoprovider should be installed");
 }
 
+fn cert_file() -> String {
+    from_env_or_else("RUSTY_RELAY_TLS_CERT_FILE", || {
+        "./certs/cert.pem".to_string()
+    })
+}
+
+fn key_file() -> String {
+    from_env_or_else("RUSTY_RELAY_TLS_KEY_FILE", || "./certs/key.pem".to_string())
+}
+
+fn client_ca_file() -> Option<String> {
+    std::env::var("RUSTY_RELAY_TLS_CLIENT_CA_FILE").ok()
+}
+
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+}
+
+/// Starts a server config that verifies client certificates against `RUSTY_RELAY_TLS_CLIENT_CA_FILE`
+/// (if set). Connections without a certificate are still accepted, `/connect` enforces it.
+pub fn server_config_builder()
+-> Result<ConfigBuilder<ServerConfig, rustls::server::WantsServerCert>, BoxError> {
+    let Some(client_ca_file) = client_ca_file() else {
+        return Ok(ServerConfig::builder().with_no_client_auth());
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&client_ca_file)? {
+        roots.add(cert?)?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?;
+
+    Ok(ServerConfig::builder().with_client_cert_verifier(verifier))
+}
+
+async fn server_config() -> Result<ServerConfig, BoxError> {
+    let cert = fs::read(cert_file()).await?;
+    let key = fs::read(key_file()).await?;
+
+    let certs = CertificateDer::pem_slice_iter(&cert).collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_slice(&key)?;
+
+    let mut config = server_config_builder()?.with_single_cert(certs, key)?;
+    config.alpn_protocols = alpn_protocols();
+
+    Ok(config)
+}
+
 pub async fn config() -> Option<RustlsConfig> {
-    RustlsConfig::from_pem_file(
-        from_env_or_else("RUSTY_RELAY_TLS_CERT_FILE", || {
-            "./certs/cert.pem".to_string()
-        }),
-        from_env_or_else("RUSTY_RELAY_TLS_KEY_FILE", || "./certs/key.pem".to_string()),
-    )
-    .await
-    .ok()
+    match server_config().await {
+        Ok(config) => Some(RustlsConfig::from_config(Arc::new(config))),
+        Err(err) => {
+            debug!("TLS is disabled: {err}");
+            None
+        }
+    }
+}
+
+/// Reloads the certificate when the files change (or on SIGHUP) so new handshakes
+/// pick it up while existing connections stay open.
+pub fn watch(config: &RustlsConfig) {
+    // an interval of 0 disables polling, leaving SIGHUP to reload
+    let interval = from_env_or_else("RUSTY_RELAY_TLS_RELOAD_INTERVAL", || 10);
+    if interval > 0 {
+        tokio::spawn(watch_files(config.clone(), Duration::from_secs(interval)));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(watch_hangup(config.clone()));
+}
+
+async fn watch_files(config: RustlsConfig, interval: Duration) {
+    let mut interval = time::interval(interval);
+    let mut last_modified = modified_at().await;
+
+    loop {
+        interval.tick().await;
+
+        let modified = modified_at().await;
+        if modified != last_modified && reload(&config).await {
+            last_modified = modified;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn watch_hangup(config: RustlsConfig) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!("failed to listen for SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP");
+        reload(&config).await;
+    }
+}
+
+async fn reload(config: &RustlsConfig) -> bool {
+    match server_config().await {
+        Ok(server_config) => {
+            config.reload_from_config(Arc::new(server_config));
+            info!("🔐 reloaded TLS certificate");
+            true
+        }
+        Err(err) => {
+            warn!("failed to reload TLS certificate, keeping the current one: {err}");
+            false
+        }
+    }
+}
+
+async fn modified_at() -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: String| async move {
+        fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    (modified(cert_file()).await, modified(key_file()).await)
+}
+
+/// The identity of a relay client, taken from its TLS client certificate.
+#[derive(Debug, Clone)]
+pub enum ClientIdentity {
+    /// Mutual TLS is not enabled
+    Disabled,
+    /// No (usable) client certificate was presented
+    Unverified,
+    /// The common name of the verified client certificate
+    Verified(String),
+}
+
+impl ClientIdentity {
+    fn from_certificates(certificates: Option<&[CertificateDer<'_>]>) -> Self {
+        certificates
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| common_name(certificate))
+            .map_or(ClientIdentity::Unverified, ClientIdentity::Verified)
+    }
+}
+
+fn common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let common_name = certificate
+        .subject()
+        .iter_common_name()
+        .next()?
+        .as_str()
+        .ok()?;
+
+    is_valid_name(common_name).then(|| common_name.to_string())
+}
+
+/// TLS acceptor that adds the [`ClientIdentity`] of the connection to every request.
+#[derive(Clone)]
+pub struct Acceptor {
+    inner: RustlsAcceptor,
+    mutual_tls: bool,
+}
+
+impl Acceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+            mutual_tls: client_ca_file().is_some(),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for Acceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientIdentity>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        let mutual_tls = self.mutual_tls;
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+
+            let identity = if mutual_tls {
+                ClientIdentity::from_certificates(stream.get_ref().1.peer_certificates())
+            } else {
+                ClientIdentity::Disabled
+            };
+
+            Ok((stream, Extension(identity).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate(common_name: &str) -> CertificateDer<'static> {
+        let mut params = rcgen::CertificateParams::default();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, common_name);
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        params.self_signed(&key_pair).unwrap().der().clone()
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let verified = ClientIdentity::from_certificates(Some(&[certificate("alice-dev")]));
+        assert!(matches!(verified, ClientIdentity::Verified(name) if name == "alice-dev"));
+
+        let invalid = ClientIdentity::from_certificates(Some(&[certificate("alice/dev")]));
+        assert!(matches!(invalid, ClientIdentity::Unverified));
+
+        let too_long = ClientIdentity::from_certificates(Some(&[certificate(&"a".repeat(65))]));
+        assert!(matches!(too_long, ClientIdentity::Unverified));
+
+        let missing = ClientIdentity::from_certificates(None);
+        assert!(matches!(missing, ClientIdentity::Unverified));
+    }
 }
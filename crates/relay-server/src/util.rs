@@ -1,13 +1,18 @@
-use axum::http::HeaderMap;
-use std::{collections::HashMap, fmt::Display, str::FromStr};
-
-pub fn get_query(params: Vec<(String, String)>) -> String {
-    let mut qs = String::new();
-    for (i, (k, v)) in params.iter().enumerate() {
-        let sep = if i == 0 { "?" } else { "&" };
-        qs.push_str(&format!("{sep}{k}={v}"));
-    }
-    qs
+use axum::http::{HeaderMap, Uri};
+use ipnet::IpNet;
+use rusty_relay_messages::Headers;
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// The path of `uri` without its first `segments` segments, still percent-encoded.
+pub fn raw_path(uri: &Uri, segments: usize) -> &str {
+    uri.path()
+        .splitn(segments + 2, '/')
+        .nth(segments + 1)
+        .unwrap_or_default()
 }
 
 pub fn from_env_or_else<T, F>(key: &str, f: F) -> T
@@ -25,30 +30,79 @@ pub fn generate_id(length: usize) -> String {
     nanoid::nanoid!(length, &nanoid::alphabet::SAFE[2..])
 }
 
-pub fn into_hashmap(headers: HeaderMap) -> HashMap<String, String> {
+pub fn into_headers(headers: HeaderMap) -> Headers {
     headers
         .iter()
-        .filter_map(|(k, v)| {
-            v.to_str()
-                .ok()
-                .map(|v| v.to_string())
-                .map(|v| (k.to_string(), v))
-        })
+        .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
         .collect()
 }
 
+/// The IP address of the caller, taken from `X-Forwarded-For` when the request
+/// comes in through one of the trusted (reverse) proxies.
+pub fn caller_ip(peer: SocketAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let peer_ip = peer.ip().to_canonical();
+    if !is_trusted(&peer_ip) {
+        return peer_ip;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .unwrap_or(peer_ip)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::util::get_query;
+    use crate::util::{caller_ip, into_headers, raw_path};
+    use axum::http::{HeaderMap, HeaderValue};
 
     #[test]
-    fn test_get_query() {
-        let qs = get_query(vec![
-            ("a".to_string(), "1".to_string()),
-            ("b".to_string(), "2".to_string()),
-            ("c".to_string(), "3".to_string()),
-            ("c".to_string(), "4".to_string()),
-        ]);
-        assert_eq!(qs, "?a=1&b=2&c=3&c=4");
+    fn test_raw_path() {
+        let uri = "/proxy/shop/files/a%2Fb/caf%C3%A9?q=a%26b".parse().unwrap();
+        assert_eq!(raw_path(&uri, 2), "files/a%2Fb/caf%C3%A9");
+        assert_eq!(raw_path(&uri, 0), "proxy/shop/files/a%2Fb/caf%C3%A9");
+
+        let uri = "/proxy/shop".parse().unwrap();
+        assert_eq!(raw_path(&uri, 2), "");
+    }
+
+    #[test]
+    fn test_caller_ip() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.2.3.4, 5.6.7.8, 10.0.0.2".parse().unwrap(),
+        );
+
+        let ip = caller_ip("10.0.0.1:1234".parse().unwrap(), &headers, &trusted);
+        assert_eq!(ip.to_string(), "5.6.7.8");
+
+        let ip = caller_ip("9.9.9.9:1234".parse().unwrap(), &headers, &trusted);
+        assert_eq!(ip.to_string(), "9.9.9.9");
+    }
+
+    #[test]
+    fn test_into_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        headers.append("x-binary", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+
+        assert_eq!(
+            into_headers(headers),
+            vec![
+                ("set-cookie".to_string(), b"a=1".to_vec()),
+                ("set-cookie".to_string(), b"b=2".to_vec()),
+                ("x-binary".to_string(), b"caf\xe9".to_vec()),
+            ]
+        );
     }
 }
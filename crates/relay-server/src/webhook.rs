@@ -1,33 +1,204 @@
-use crate::{error::HttpError, state::AppState, util};
+use crate::{
+    error::HttpError,
+    forwarded::Forwarded,
+    proxy,
+    state::AppState,
+    tls::ClientIdentity,
+    util::{self, generate_id},
+};
 use axum::{
-    extract::{Path, State},
+    Extension,
+    extract::{ConnectInfo, Path, State},
     http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
 use rusty_relay_messages::RelayMessage;
-use std::sync::Arc;
-use tracing::info;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{info, warn};
 
 #[tracing::instrument(skip(state))]
 pub async fn webhook_handler(
     state: State<Arc<AppState>>,
-    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    mut headers: HeaderMap,
     method: axum::http::Method,
     Path(client_id): Path<String>,
     body: axum::body::Bytes,
 ) -> impl IntoResponse {
     info!("📩 webhook received");
 
-    if let Some(sender) = state.get_client(&client_id).await {
-        let _ = sender.send(RelayMessage::Webhook {
-            method: method.to_string(),
-            body: body.to_vec(),
-            headers: util::into_hashmap(headers),
-        });
-    } else {
-        return HttpError::BadRequest(format!("Client id is unknown: {}", client_id))
-            .into_response();
+    let forwarded = Forwarded::new(addr, &headers, state.trusted_proxies(), identity.is_some());
+    let config = match state.client_config(&client_id).await {
+        Ok(config) => config,
+        Err(err) => return err.into_response(),
+    };
+
+    let caller_ip = forwarded.caller_ip;
+    if let Err(err) = state.check_rate_limit(&client_id, caller_ip).await {
+        warn!(%caller_ip, "❌ webhook rate limited");
+        return err.into_response();
+    }
+
+    if let Some(signature) = config.signature.as_ref() {
+        if let Err(reason) = signature.verify(&headers, &body) {
+            warn!("❌ webhook rejected: {reason}");
+            return HttpError::Unauthorized("Webhook signature is invalid".to_string())
+                .into_response();
+        }
+    }
+
+    forwarded.apply(&mut headers);
+    let webhook = RelayMessage::Webhook {
+        method: method.to_string(),
+        body: body.to_vec(),
+        headers: util::into_headers(headers),
+    };
+    let timeout = config.proxy_timeout(None).unwrap_or(state.proxy_timeout());
+    if let Err(err) = deliver_anywhere(&state, &client_id, webhook, timeout).await {
+        return err.into_response();
     }
 
     StatusCode::OK.into_response()
 }
+
+/// Sends the webhook to the instance the client is connected to when it isn't connected here,
+/// and awaits whether that instance could deliver it.
+async fn deliver_anywhere(
+    state: &Arc<AppState>,
+    client_id: &str,
+    webhook: RelayMessage,
+    timeout: Duration,
+) -> Result<(), HttpError> {
+    if !state.get_clients(client_id).await.is_empty() {
+        return deliver(state, client_id, webhook).await;
+    }
+
+    let location = match state.backplane().locate(client_id).await {
+        Ok(Some(location)) => location,
+        Ok(None) => {
+            return Err(HttpError::BadRequest(format!(
+                "Client id is unknown: {}",
+                client_id
+            )));
+        }
+        Err(err) => {
+            warn!(client_id, "failed to locate client on backplane: {err}");
+            return Err(HttpError::ServiceUnavailable(
+                "Backplane is unavailable".to_string(),
+            ));
+        }
+    };
+
+    let webhook_id = generate_id(20);
+    let response = proxy::forward_remote(
+        state,
+        &location.instance_id,
+        client_id,
+        &webhook_id,
+        webhook,
+        timeout,
+    )
+    .await?;
+
+    match response {
+        RelayMessage::ProxyResponse { status: 200, .. } => Ok(()),
+        RelayMessage::ProxyResponse { status, body, .. } => {
+            let message = String::from_utf8_lossy(&body).to_string();
+            Err(match StatusCode::from_u16(status) {
+                Ok(StatusCode::BAD_REQUEST) => HttpError::BadRequest(message),
+                Ok(StatusCode::SERVICE_UNAVAILABLE) => HttpError::ServiceUnavailable(message),
+                _ => HttpError::BadGateway(message),
+            })
+        }
+        _ => Err(HttpError::BadGateway("Unexpected response".to_string())),
+    }
+}
+
+/// Sends the webhook to all clients connected under `client_id` (fan out) or to the first
+/// selected client that accepts it.
+pub async fn deliver(
+    state: &AppState,
+    client_id: &str,
+    webhook: RelayMessage,
+) -> Result<(), HttpError> {
+    let unavailable = || HttpError::ServiceUnavailable("Client is overloaded".to_string());
+
+    if state.webhook_fanout() {
+        let clients = state.get_clients(client_id).await;
+        let delivered = clients
+            .iter()
+            .filter(|client| client.send(webhook.clone()).is_ok())
+            .count();
+        if delivered < clients.len() {
+            warn!(
+                delivered,
+                clients = clients.len(),
+                "❌ webhook was not delivered to all clients"
+            );
+        }
+
+        return if delivered > 0 {
+            Ok(())
+        } else {
+            Err(unavailable())
+        };
+    }
+
+    let mut tried = Vec::new();
+    while let Some(client) = state.select_client(client_id, &tried).await {
+        if client.send(webhook.clone()).is_ok() {
+            return Ok(());
+        }
+        tried.push(client.connection_id);
+    }
+
+    Err(unavailable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backplane::{self, InMemoryBackplane},
+        state::ClientConfig,
+    };
+
+    #[tokio::test]
+    async fn test_deliver_across_instances() {
+        let backplane_a = InMemoryBackplane::new("a".to_string());
+        let backplane_b = backplane_a.join("b".to_string());
+        let state_a = Arc::new(AppState::with_backplane(Arc::new(backplane_a)));
+        let state_b = Arc::new(AppState::with_backplane(Arc::new(backplane_b)));
+        backplane::start(state_a.clone()).await.unwrap();
+        backplane::start(state_b.clone()).await.unwrap();
+
+        let (client, mut rx) = state_b
+            .add_client("shop", false, ClientConfig::default())
+            .await
+            .unwrap();
+        let webhook = || RelayMessage::Webhook {
+            method: "POST".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        let timeout = state_a.proxy_timeout();
+
+        assert!(
+            deliver_anywhere(&state_a, "shop", webhook(), timeout)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            rx.recv().await,
+            Some(RelayMessage::Webhook { .. })
+        ));
+
+        // the sender learns that the client on the other instance is overloaded
+        while client.send(webhook()).is_ok() {}
+        assert!(matches!(
+            deliver_anywhere(&state_a, "shop", webhook(), timeout).await,
+            Err(HttpError::ServiceUnavailable(_))
+        ));
+    }
+}
@@ -1,31 +1,43 @@
-use crate::{error::HttpError, state::AppState, util::generate_id};
+use crate::{
+    error::HttpError,
+    state::{AppState, ClientConfig},
+    tls::ClientIdentity,
+    util::generate_id,
+};
 use axum::{
+    Extension,
     extract::{
         State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket, close_code},
     },
     http::HeaderMap,
     response::IntoResponse,
 };
-use rusty_relay_messages::RelayMessage;
+use rusty_relay_messages::{CLIENT_OPTIONS_HEADER, ClientOptions, RelayMessage};
 use std::sync::Arc;
 use tokio::time;
 use tokio_stream::StreamExt;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-#[tracing::instrument(skip(ws, state))]
+#[tracing::instrument(skip(ws, headers, state))]
 pub async fn connect_handler(
     ws: WebSocketUpgrade,
     headers: HeaderMap,
+    identity: Option<Extension<ClientIdentity>>,
     state: State<Arc<AppState>>,
 ) -> impl IntoResponse {
     match headers.get("PRIVATE-TOKEN") {
         Some(token) => match token.to_str() {
             Ok(token) => {
                 if token == state.connect_token() {
-                    let client_id = generate_id(12);
-                    info!(client_id, "👨 client connected");
-                    ws.on_upgrade(move |socket| handle_ws(socket, client_id, state))
+                    let identity = identity.map(|Extension(identity)| identity);
+                    match accept_client(&state, identity, &headers).await {
+                        Ok(accepted) => {
+                            info!(client_id = accepted.client_id, "👨 client connected");
+                            ws.on_upgrade(move |socket| handle_ws(socket, accepted, state))
+                        }
+                        Err(err) => err.into_response(),
+                    }
                 } else {
                     debug!("❌ client provided invalid token");
                     HttpError::Unauthorized("Connection token is invalid".to_string())
@@ -46,20 +58,133 @@ pub async fn connect_handler(
     }
 }
 
-#[tracing::instrument(skip(socket, state))]
-async fn handle_ws(mut socket: WebSocket, client_id: String, state: State<Arc<AppState>>) {
+/// A client that is allowed to connect.
+struct Accepted {
+    client_id: String,
+    named: bool,
+    config: ClientConfig,
+}
+
+async fn accept_client(
+    state: &AppState,
+    identity: Option<ClientIdentity>,
+    headers: &HeaderMap,
+) -> Result<Accepted, HttpError> {
+    let options = match headers.get(CLIENT_OPTIONS_HEADER) {
+        Some(value) => {
+            serde_json::from_slice::<ClientOptions>(value.as_bytes()).map_err(|err| {
+                debug!("❌ client provided invalid options");
+                HttpError::BadRequest(format!("Client options are invalid: {err}"))
+            })?
+        }
+        None => ClientOptions::default(),
+    };
+
+    let identity = client_identity(identity)?;
+    let (client_id, named) = match options.name.clone() {
+        // a certificate only grants its own name, not the client id of another certificate
+        Some(name) if identity.as_ref().is_some_and(|identity| *identity != name) => {
+            debug!("❌ client name does not match its certificate");
+            return Err(HttpError::Forbidden(format!(
+                "Client name does not match the client certificate: {name}"
+            )));
+        }
+        Some(name) if is_valid_name(&name) => (name, true),
+        Some(name) => {
+            return Err(HttpError::BadRequest(format!(
+                "Client name is invalid (use 1-64 of a-z, A-Z, 0-9, -, _ and ., not starting with .): {name}"
+            )));
+        }
+        None => (identity.unwrap_or_else(|| generate_id(12)), false),
+    };
+
+    let config = ClientConfig::try_from(options)?;
+    if let Some(timeout) = config
+        .max_proxy_timeout()
+        .filter(|timeout| *timeout > state.max_proxy_timeout())
+    {
+        return Err(HttpError::BadRequest(format!(
+            "Proxy timeout of {}s exceeds the maximum of {}s",
+            timeout.as_secs(),
+            state.max_proxy_timeout().as_secs()
+        )));
+    }
+    state
+        .can_join(&client_id, named, &config)
+        .await
+        .inspect_err(|_| {
+            debug!(client_id, "❌ client id is already in use");
+        })?;
+
+    Ok(Accepted {
+        client_id,
+        named,
+        config,
+    })
+}
+
+/// The verified name from the client certificate, if mutual TLS is enabled.
+fn client_identity(identity: Option<ClientIdentity>) -> Result<Option<String>, HttpError> {
+    match identity.unwrap_or(ClientIdentity::Disabled) {
+        ClientIdentity::Disabled => Ok(None),
+        ClientIdentity::Unverified => {
+            debug!("❌ client did not provide a valid certificate");
+            Err(HttpError::Unauthorized(
+                "Client certificate is missing or invalid".to_string(),
+            ))
+        }
+        ClientIdentity::Verified(name) => Ok(Some(name)),
+    }
+}
+
+/// Whether `name` can be used as client id, for both `--name` and the client certificate.
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[tracing::instrument(skip_all, fields(client_id = accepted.client_id))]
+async fn handle_ws(mut socket: WebSocket, accepted: Accepted, state: State<Arc<AppState>>) {
+    let client_id = accepted.client_id;
+
+    // another client may have taken the id during the upgrade
+    let (client, mut rx_relay) = match state
+        .add_client(&client_id, accepted.named, accepted.config)
+        .await
+    {
+        Ok(added) => added,
+        Err(err) => {
+            debug!(?err, "❌ client id was taken during the upgrade");
+            let reason = match err {
+                HttpError::Conflict(message) => message,
+                err => format!("{err:?}"),
+            };
+            let close = CloseFrame {
+                code: close_code::POLICY,
+                reason: reason.into(),
+            };
+            let _ = socket.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
     if let Ok(msg) = serde_json::to_string(&RelayMessage::ClientId(client_id.clone())) {
         if socket.send(Message::Text(msg.into())).await.is_err() {
             error!("failed to send message to client");
+            state.remove_client(&client_id, &client.connection_id).await;
             return;
         }
     } else {
         error!("failed to serialize into JSON");
+        state.remove_client(&client_id, &client.connection_id).await;
         return;
     }
 
-    let mut rx_relay = state.add_client(&client_id).await;
     let mut ping_interval = time::interval(state.ping_interval());
+    let mut undelivered = Vec::new();
 
     loop {
         tokio::select! {
@@ -69,7 +194,7 @@ async fn handle_ws(mut socket: WebSocket, client_id: String, state: State<Arc<Ap
                     break;
                 }
             }
-            Ok(relay_message) = rx_relay.recv() => {
+            Some(relay_message) = rx_relay.recv() => {
                 if let Ok(msg) = serde_json::to_string(&relay_message) {
                     if socket
                         .send(Message::Text(msg.into()))
@@ -77,10 +202,12 @@ async fn handle_ws(mut socket: WebSocket, client_id: String, state: State<Arc<Ap
                         .is_err()
                     {
                         error!("failed to send message to client");
+                        undelivered.push(relay_message);
                         break;
                     }
                 } else {
                     error!("failed to serialize into JSON");
+                    undelivered.push(relay_message);
                     break;
                 }
             }
@@ -91,21 +218,35 @@ async fn handle_ws(mut socket: WebSocket, client_id: String, state: State<Arc<Ap
                         break;
                     }
                     Ok(Message::Text(message)) => {
-                        if let Ok(RelayMessage::ProxyResponse { request_id, body, headers, status }) = serde_json::from_slice::<RelayMessage>(message.as_bytes()) {
-                            let body_str = std::str::from_utf8(&body).unwrap_or("<binary>");
-                            debug!(
-                                request_id,
-                                status,
-                                ?headers,
-                                body_str,
-                                "received proxy response from client"
-                                );
-                            if let Some(tx) = state.remove_proxy_request(&request_id).await {
-                                let _ = tx.send(RelayMessage::ProxyResponse { request_id, body, headers, status });
-                            }
-                       } else {
+                        let Ok(response) = serde_json::from_slice::<RelayMessage>(message.as_bytes()) else {
                             error!("failed to deserialize from bytes: {}", message);
-                       }
+                            continue;
+                        };
+
+                        let request_id = match &response {
+                            RelayMessage::ProxyResponse { request_id, body, headers, status } => {
+                                let body_str = std::str::from_utf8(body).unwrap_or("<binary>");
+                                debug!(
+                                    request_id,
+                                    status,
+                                    ?headers,
+                                    body_str,
+                                    "received proxy response from client"
+                                    );
+                                request_id
+                            }
+                            RelayMessage::ProxyError { request_id, kind, message } => {
+                                warn!(request_id, ?kind, message, "❌ client failed to proxy request");
+                                request_id
+                            }
+                            _ => {
+                                warn!("unexpected message from client: {}", message);
+                                continue;
+                            }
+                        };
+                        if let Some(tx) = state.take_proxy_request(request_id, &client.connection_id).await {
+                            let _ = tx.send(response);
+                        }
                     }
                     Ok(_) => {},
                     Err(err) => {
@@ -117,7 +258,95 @@ async fn handle_ws(mut socket: WebSocket, client_id: String, state: State<Arc<Ap
         }
     }
 
-    state.remove_client(&client_id).await;
+    state.remove_client(&client_id, &client.connection_id).await;
+    // callers waiting on this connection fail over to another client with the same name
+    state.remove_proxy_requests(&client.connection_id).await;
+
+    rx_relay.close();
+    while let Ok(relay_message) = rx_relay.try_recv() {
+        undelivered.push(relay_message);
+    }
+
+    let mut webhooks = 0;
+    for relay_message in undelivered {
+        if let RelayMessage::Webhook { .. } = relay_message {
+            let delivered = match state.select_client(&client_id, &[]).await {
+                Some(other) => other.send(relay_message).is_ok(),
+                None => false,
+            };
+            if !delivered {
+                webhooks += 1;
+            }
+        }
+    }
+    if webhooks > 0 {
+        warn!(
+            webhooks,
+            "❌ accepted webhooks were not delivered to the client"
+        );
+    }
 
-    info!("👨 client disconnected");
+    let dropped = client.dropped();
+    info!(dropped, "👨 client disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options_header(options: &ClientOptions) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CLIENT_OPTIONS_HEADER,
+            serde_json::to_string(options).unwrap().parse().unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_certificate_name() {
+        let state = AppState::new();
+        let alice = || Some(ClientIdentity::Verified("alice".to_string()));
+
+        let accepted = accept_client(&state, alice(), &HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(accepted.client_id, "alice");
+
+        let headers = options_header(&ClientOptions {
+            name: Some("alice".to_string()),
+            ..Default::default()
+        });
+        let accepted = accept_client(&state, alice(), &headers).await.unwrap();
+        assert_eq!(accepted.client_id, "alice");
+        assert!(accepted.named);
+
+        // the certificate of alice can't be used to connect as bob
+        let headers = options_header(&ClientOptions {
+            name: Some("bob".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            accept_client(&state, alice(), &headers).await,
+            Err(HttpError::Forbidden(_))
+        ));
+
+        // a dotted common name can be used with --name as well
+        let dotted = || Some(ClientIdentity::Verified("alice.example.com".to_string()));
+        let headers = options_header(&ClientOptions {
+            name: Some("alice.example.com".to_string()),
+            ..Default::default()
+        });
+        let accepted = accept_client(&state, dotted(), &headers).await.unwrap();
+        assert_eq!(accepted.client_id, "alice.example.com");
+
+        let headers = options_header(&ClientOptions {
+            name: Some("..".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            accept_client(&state, None, &headers).await,
+            Err(HttpError::BadRequest(_))
+        ));
+    }
 }